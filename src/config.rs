@@ -0,0 +1,241 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bitcoincore_rpc::{Auth, bitcoin};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::{
+    App, db::RankingKind, error::TrackerError, indexer::IndexerConfig, parse_proxy_auth,
+    server::MonitorConfig,
+};
+
+/// Name of the config file, looked up in the base datadir.
+const CONFIG_FILE: &str = "tracker.toml";
+
+const DEFAULT_AUTH: &str = "username:password";
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+const DEFAULT_CONTROL_PORT: u16 = 9051;
+const DEFAULT_SOCKS_PORT: u16 = 9050;
+const DEFAULT_PROBE_PARALLELISM: usize = 16;
+const DEFAULT_PROBE_INTERVAL: u64 = 5 * 60;
+const DEFAULT_PROBE_TICK: u64 = 10;
+const DEFAULT_PROBE_ATTEMPTS: u32 = 3;
+const DEFAULT_PROBE_RETRY_DELAY: u64 = 1;
+const DEFAULT_MAX_PROBE_BACKOFF: u64 = 6 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    /// The chain bitcoind reports for this network.
+    pub fn chain(self) -> bitcoin::Network {
+        match self {
+            Network::Mainnet => bitcoin::Network::Bitcoin,
+            Network::Testnet => bitcoin::Network::Testnet,
+            Network::Signet => bitcoin::Network::Signet,
+            Network::Regtest => bitcoin::Network::Regtest,
+        }
+    }
+
+    fn rpc_port(self) -> u16 {
+        match self {
+            Network::Mainnet => 8332,
+            Network::Testnet => 18332,
+            Network::Signet => 38332,
+            Network::Regtest => 18443,
+        }
+    }
+
+    /// A height comfortably before the first maker announcements, so that fresh trackers don't
+    /// scan years of chain that can't contain any.
+    fn start_height(self) -> u64 {
+        match self {
+            Network::Mainnet => 750_000,
+            Network::Testnet => 2_300_000,
+            Network::Signet => 100_000,
+            Network::Regtest => 0,
+        }
+    }
+
+    /// Where this network's state lives, so that no two networks share a registry. Regtest uses
+    /// the base datadir itself, where state lived before there were networks to choose from, so
+    /// upgrading keeps the onion address, registry and checkpoint; the others a subfolder named
+    /// after them.
+    fn datadir(self, base: &Path) -> PathBuf {
+        match self {
+            Network::Mainnet => base.join("mainnet"),
+            Network::Testnet => base.join("testnet"),
+            Network::Signet => base.join("signet"),
+            Network::Regtest => base.to_path_buf(),
+        }
+    }
+}
+
+/// Contents of `tracker.toml`. Keys match the long command line flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct FileConfig {
+    network: Option<Network>,
+    rpc: Option<String>,
+    auth: Option<String>,
+    rpc_cookie: Option<PathBuf>,
+    address: Option<String>,
+    control_port: Option<u16>,
+    tor_auth_password: Option<String>,
+    socks_port: Option<u16>,
+    start_height: Option<u64>,
+    no_mempool: Option<bool>,
+    probe_parallelism: Option<usize>,
+    probe_interval: Option<u64>,
+    probe_tick: Option<u64>,
+    probe_attempts: Option<u32>,
+    probe_retry_delay: Option<u64>,
+    max_probe_backoff: Option<u64>,
+    evict_after: Option<u32>,
+    ranking: Option<RankingKind>,
+}
+
+impl FileConfig {
+    /// Reads the config file from `datadir`, which is fine to be missing.
+    fn load(datadir: &Path) -> Result<Self, TrackerError> {
+        let path = datadir.join(CONFIG_FILE);
+        if !path.exists() {
+            return Ok(FileConfig::default());
+        }
+        let contents = fs::read_to_string(&path)?;
+        toml::from_str(&contents)
+            .map_err(|e| TrackerError::General(format!("invalid {}: {e}", path.display())))
+    }
+}
+
+/// The tracker's settings, once the command line, environment, config file and network profile
+/// have been layered.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub network: Network,
+    /// The network's datadir, see [`Network::datadir`].
+    pub datadir: PathBuf,
+    pub rpc: String,
+    pub auth: Auth,
+    pub address: String,
+    pub control_port: u16,
+    pub tor_auth_password: String,
+    pub ranking: RankingKind,
+    pub indexer: IndexerConfig,
+    pub monitor: MonitorConfig,
+}
+
+impl Settings {
+    /// Layers the parsed command line, which clap has already merged with the environment, over
+    /// the config file and then the network profile.
+    pub fn resolve(args: App) -> Result<Self, TrackerError> {
+        let base_datadir = PathBuf::from(&args.datadir);
+        let file = FileConfig::load(&base_datadir)?;
+        let network = args.network.or(file.network).unwrap_or(Network::Regtest);
+
+        // Whichever of a password and a cookie file comes from the higher layer wins.
+        let auth = match (args.auth, args.rpc_cookie, file.auth, file.rpc_cookie) {
+            (Some((user, password)), ..) => Auth::UserPass(user, password),
+            (None, Some(cookie), ..) => Auth::CookieFile(cookie),
+            (None, None, Some(auth), _) => {
+                let (user, password) = parse_proxy_auth(&auth)?;
+                Auth::UserPass(user, password)
+            }
+            (None, None, None, Some(cookie)) => Auth::CookieFile(cookie),
+            (None, None, None, None) => {
+                let (user, password) = parse_proxy_auth(DEFAULT_AUTH)?;
+                Auth::UserPass(user, password)
+            }
+        };
+        let probe_tick = args
+            .probe_tick
+            .or(file.probe_tick)
+            .unwrap_or(DEFAULT_PROBE_TICK);
+        let attempts = args
+            .probe_attempts
+            .or(file.probe_attempts)
+            .unwrap_or(DEFAULT_PROBE_ATTEMPTS);
+        let parallelism = args
+            .probe_parallelism
+            .or(file.probe_parallelism)
+            .unwrap_or(DEFAULT_PROBE_PARALLELISM);
+        let evict_after = args.evict_after.or(file.evict_after);
+        if probe_tick == 0 || attempts == 0 || parallelism == 0 || evict_after == Some(0) {
+            return Err(TrackerError::General(
+                "probe-tick, probe-attempts, probe-parallelism and evict-after must be at least 1"
+                    .to_string(),
+            ));
+        }
+
+        let secs = |arg: Option<u64>, file: Option<u64>, default: u64| {
+            Duration::from_secs(arg.or(file).unwrap_or(default))
+        };
+        Ok(Settings {
+            network,
+            datadir: network.datadir(&base_datadir),
+            rpc: args
+                .rpc
+                .or(file.rpc)
+                .unwrap_or_else(|| format!("127.0.0.1:{}", network.rpc_port())),
+            auth,
+            address: args
+                .address
+                .or(file.address)
+                .unwrap_or_else(|| DEFAULT_ADDRESS.to_string()),
+            control_port: args
+                .control_port
+                .or(file.control_port)
+                .unwrap_or(DEFAULT_CONTROL_PORT),
+            tor_auth_password: args
+                .tor_auth_password
+                .or(file.tor_auth_password)
+                .unwrap_or_default(),
+            ranking: args
+                .ranking
+                .or(file.ranking)
+                .unwrap_or(RankingKind::BondValue),
+            indexer: IndexerConfig {
+                start_height: args
+                    .start_height
+                    .or(file.start_height)
+                    .unwrap_or_else(|| network.start_height()),
+                scan_mempool: !args.no_mempool.or(file.no_mempool).unwrap_or(false),
+            },
+            monitor: MonitorConfig {
+                socks_port: args
+                    .socks_port
+                    .or(file.socks_port)
+                    .unwrap_or(DEFAULT_SOCKS_PORT),
+                parallelism,
+                probe_interval: secs(
+                    args.probe_interval,
+                    file.probe_interval,
+                    DEFAULT_PROBE_INTERVAL,
+                ),
+                schedule_tick: Duration::from_secs(probe_tick),
+                attempts,
+                retry_delay: secs(
+                    args.probe_retry_delay,
+                    file.probe_retry_delay,
+                    DEFAULT_PROBE_RETRY_DELAY,
+                ),
+                max_backoff: secs(
+                    args.max_probe_backoff,
+                    file.max_probe_backoff,
+                    DEFAULT_MAX_PROBE_BACKOFF,
+                ),
+                evict_after,
+            },
+        })
+    }
+}
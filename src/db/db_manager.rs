@@ -1,21 +1,92 @@
-use std::collections::HashMap;
-use tokio::sync::mpsc::Receiver;
-use tracing::info;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+use tokio::{
+    sync::mpsc::Receiver,
+    time::{Instant, MissedTickBehavior, interval},
+};
+use tracing::{error, info, warn};
 
+use super::{
+    query::{MAX_PAGE_SIZE, active, query_filtered},
+    ranking::{RankingKind, bond_value, weighted_sample},
+    store::Store,
+};
 use crate::{
     error::TrackerError,
     status::{self, Status},
-    types::{DbRequest, ServerInfo},
+    types::{Checkpoint, DbRequest, FidelityBond, MakerRecord, RejectReason, ServerInfo},
 };
 
-pub async fn run(mut rx: Receiver<DbRequest>, status_tx: status::Sender) {
-    let mut servers: HashMap<String, ServerInfo> = HashMap::new();
-    info!("DB manager started");
-    while let Some(request) = rx.recv().await {
+/// How often changes to the registry are written out. Writing the whole snapshot on every change
+/// would cost a write and fsync per probe result.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+pub async fn run(
+    mut rx: Receiver<DbRequest>,
+    status_tx: status::Sender,
+    datadir: PathBuf,
+    ranking: RankingKind,
+) {
+    let store = Store::new(&datadir);
+    let policy = ranking.policy();
+    let loaded = async {
+        let (servers, dropped) = store.load_servers().await?;
+        let mut checkpoint = store.load_checkpoint().await?;
+        if dropped > 0 {
+            // Those blocks were indexed without bond checks, so bonded announcements in them
+            // may have been missed.
+            warn!("Dropped {dropped} makers without a fidelity bond, re-indexing from scratch");
+            checkpoint = None;
+        }
+        Ok::<_, TrackerError>((servers, checkpoint))
+    };
+    let (mut servers, mut checkpoint) = match loaded.await {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("Failed to load maker registry: {e:?}");
+            let _ = status_tx
+                .send(Status {
+                    state: status::State::DBShutdown(e),
+                })
+                .await;
+            return;
+        }
+    };
+    let mut tip_height = checkpoint_tip(&checkpoint);
+    for info in servers.values_mut() {
+        info.bond_value = bond_value(&info.bond, tip_height);
+    }
+    info!(
+        "DB manager started with {} known makers, indexed up to height {tip_height}",
+        servers.len()
+    );
+    // Set while the registry has changes not yet written out.
+    let mut dirty = false;
+    let mut flush = interval(FLUSH_INTERVAL);
+    flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let request = tokio::select! {
+            request = rx.recv() => match request {
+                Some(request) => request,
+                None => break,
+            },
+            _ = flush.tick() => {
+                let _ = flush_servers(&store, &servers, &mut dirty).await;
+                continue;
+            }
+        };
         match request {
             DbRequest::Add(addr, info) => {
                 info!("Add request intercepted: address: {addr:?}, info: {info:?}");
-                servers.insert(addr, info);
+                if let Err(reason) = add(&mut servers, addr, info, tip_height) {
+                    info!("Announcement not taken: {reason:?}");
+                }
+                dirty = true;
+            }
+            DbRequest::Register(addr, info, resp_tx) => {
+                info!("Register request intercepted: address: {addr:?}, info: {info:?}");
+                let result = add(&mut servers, addr, info, tip_height);
+                dirty = true;
+                let _ = resp_tx.send(result).await;
             }
             DbRequest::Query(addr, resp_tx) => {
                 info!("Query request intecepted");
@@ -24,7 +95,60 @@ pub async fn run(mut rx: Receiver<DbRequest>, status_tx: status::Sender) {
             }
             DbRequest::Update(addr, server_info) => {
                 info!("Update request intercepted");
-                servers.insert(addr, server_info);
+                let bond_value = bond_value(&server_info.bond, tip_height);
+                servers.insert(
+                    addr,
+                    ServerInfo {
+                        bond_value,
+                        ..server_info
+                    },
+                );
+                dirty = true;
+            }
+            DbRequest::MarkLive(addr) => {
+                info!("Mark live request intercepted: address: {addr:?}");
+                if let Some(info) = servers.get_mut(&addr) {
+                    mark_live(info);
+                    info.cooldown = Instant::now();
+                    dirty = true;
+                }
+            }
+            DbRequest::RecordHeartbeat(addr) => {
+                info!("Record heartbeat request intercepted: address: {addr:?}");
+                if let Some(info) = servers.get_mut(&addr) {
+                    info.history.record_heartbeat();
+                    dirty = true;
+                }
+            }
+            DbRequest::RecordProbe {
+                address,
+                latency,
+                rekeyed,
+            } => {
+                info!(
+                    "Record probe request intercepted: address: {address:?}, latency: {latency:?}"
+                );
+                if let Some(info) = servers.get_mut(&address) {
+                    if rekeyed && let Some(bond) = info.pending_bond.take() {
+                        info!("Maker {address:?} proved its new bond {}", bond.outpoint);
+                        info.bond_value = bond_value(&bond, tip_height);
+                        info.bond = bond;
+                        info.unconfirmed = false;
+                    }
+                    info.history.record(latency);
+                    if latency.is_some() {
+                        mark_live(info);
+                    } else {
+                        info.stale = true;
+                    }
+                    dirty = true;
+                }
+            }
+            DbRequest::Evict(addr) => {
+                info!("Evict request intercepted: address: {addr:?}");
+                if servers.remove(&addr).is_some() {
+                    dirty = true;
+                }
             }
             DbRequest::QueryAll(resp_tx) => {
                 info!("Query all request intercepted");
@@ -34,19 +158,408 @@ pub async fn run(mut rx: Receiver<DbRequest>, status_tx: status::Sender) {
             }
             DbRequest::QueryActive(resp_tx) => {
                 info!("Query active intercepted");
-                let response: Vec<String> = servers
-                    .iter()
-                    .filter(|x| !x.1.stale)
-                    .map(|e| e.0.clone())
+                let response = policy.rank(active(&servers));
+                let _ = resp_tx.send(response).await;
+            }
+            DbRequest::QuerySample {
+                count,
+                seed,
+                resp_tx,
+            } => {
+                info!("Query sample intercepted: count: {count}, seed: {seed:?}");
+                let count = count.min(MAX_PAGE_SIZE);
+                let response = weighted_sample(active(&servers), count, seed)
+                    .into_iter()
+                    .map(|(addr, info)| MakerRecord::new(addr, info))
                     .collect();
                 let _ = resp_tx.send(response).await;
             }
+            DbRequest::QueryFiltered(filter, resp_tx) => {
+                info!("Query filtered intercepted: {filter:?}");
+                let _ = resp_tx.send(query_filtered(&servers, &filter)).await;
+            }
+            DbRequest::Rollback(height) => {
+                info!("Rollback request intercepted: height: {height}");
+                servers.retain(|_, info| info.found_height.is_none_or(|h| h <= height));
+                for info in servers.values_mut() {
+                    let reorged = |bond: &FidelityBond| {
+                        bond.conf_height.is_some_and(|h| u64::from(h) > height)
+                    };
+                    if info.pending_bond.as_ref().is_some_and(reorged) {
+                        info.pending_bond = None;
+                    }
+                }
+                dirty = true;
+            }
+            DbRequest::DropUnconfirmed(addr) => {
+                info!("Drop unconfirmed request intercepted: address: {addr:?}");
+                if servers.get(&addr).is_some_and(|info| info.unconfirmed) {
+                    servers.remove(&addr);
+                    dirty = true;
+                }
+            }
+            DbRequest::ClearUnconfirmed => {
+                info!("Clear unconfirmed request intercepted");
+                servers.retain(|_, info| !info.unconfirmed);
+                dirty = true;
+            }
+            DbRequest::SetCheckpoint(new_checkpoint) => {
+                info!(
+                    "Checkpoint request intercepted: {:?}",
+                    new_checkpoint.blocks.last()
+                );
+                // The checkpoint must never get ahead of the registry it vouches for, so it is only
+                // written once the registry is. If not, a crash re-scans from the last one that was.
+                if flush_servers(&store, &servers, &mut dirty).await {
+                    persist_checkpoint(&store, &new_checkpoint).await;
+                }
+                checkpoint = Some(new_checkpoint);
+                tip_height = checkpoint_tip(&checkpoint);
+                for info in servers.values_mut() {
+                    info.bond_value = bond_value(&info.bond, tip_height);
+                }
+            }
+            DbRequest::QueryCheckpoint(resp_tx) => {
+                info!("Query checkpoint intercepted");
+                let _ = resp_tx.send(checkpoint.clone()).await;
+            }
         }
     }
 
+    // Every sender is gone, which is how a shutdown reaches us: flush before exiting.
+    if flush_servers(&store, &servers, &mut dirty).await
+        && let Some(checkpoint) = &checkpoint
+    {
+        persist_checkpoint(&store, checkpoint).await;
+    }
+    info!("DB manager flushed and stopped");
+
     let _ = status_tx
         .send(Status {
             state: status::State::DBShutdown(TrackerError::DbManagerExited),
         })
         .await;
 }
+
+/// Adds or refreshes the entry for `addr`.
+///
+/// A bond only shows that its holder controls some bond, not the address. So an address keeps
+/// the key it was first registered with: a bond under another key is set aside as pending until
+/// the maker proves it holds that key too, and one bond never backs two addresses.
+fn add(
+    servers: &mut HashMap<String, ServerInfo>,
+    addr: String,
+    info: ServerInfo,
+    tip_height: u64,
+) -> Result<(), RejectReason> {
+    let outpoint = info.bond.outpoint;
+    let in_use = servers.iter().any(|(other, s)| {
+        *other != addr
+            && (s.bond.outpoint == outpoint
+                || s.pending_bond
+                    .as_ref()
+                    .is_some_and(|b| b.outpoint == outpoint))
+    });
+    if in_use {
+        return Err(RejectReason::BondInUse);
+    }
+
+    let existing = servers.get_mut(&addr);
+    // A mempool sighting says nothing about an entry that already confirmed, and taking its bond
+    // would let anyone broadcasting a transaction replace the confirmed one.
+    if info.unconfirmed && existing.as_ref().is_some_and(|s| !s.unconfirmed) {
+        info!("Ignoring unconfirmed announcement of confirmed maker {addr:?}");
+        return Ok(());
+    }
+    if let Some(existing) = existing
+        && existing.bond.pubkey != info.bond.pubkey
+    {
+        // Only a confirmed bond may wait for its proof; an unconfirmed one could be evicted.
+        if !info.unconfirmed {
+            existing.pending_bond = Some(info.bond);
+        }
+        return Err(RejectReason::AddressTaken);
+    }
+    let existing = servers.get(&addr);
+    let found_height = match (existing.and_then(|s| s.found_height), info.found_height) {
+        (Some(existing), Some(new)) => Some(existing.min(new)),
+        (existing, new) => existing.or(new),
+    };
+    // Re-announcing doesn't interrupt the uptime of a maker that is already live.
+    let live_since = existing
+        .filter(|s| !s.stale)
+        .map_or(info.live_since, |s| s.live_since);
+    let last_ping = info.last_ping.or(existing.and_then(|s| s.last_ping));
+    let cooldown = existing.map_or(info.cooldown, |s| s.cooldown);
+    let history = existing.map_or(info.history.clone(), |s| s.history.clone());
+    servers.insert(
+        addr,
+        ServerInfo {
+            cooldown,
+            live_since,
+            last_ping,
+            history,
+            found_height,
+            pending_bond: existing.and_then(|s| s.pending_bond.clone()),
+            bond_value: bond_value(&info.bond, tip_height),
+            ..info
+        },
+    );
+    Ok(())
+}
+
+fn mark_live(info: &mut ServerInfo) {
+    let now = Instant::now();
+    if info.stale {
+        info.live_since = now;
+    }
+    info.stale = false;
+    info.last_ping = Some(now);
+}
+
+fn checkpoint_tip(checkpoint: &Option<Checkpoint>) -> u64 {
+    checkpoint
+        .as_ref()
+        .and_then(|c| c.blocks.last())
+        .map_or(0, |(height, _)| *height)
+}
+
+/// Writes out the registry if it has unsaved changes, returning whether it is now on disk. A failed
+/// write is logged rather than fatal: the in-memory registry stays authoritative and remains dirty,
+/// so the next flush retries.
+#[must_use]
+async fn flush_servers(
+    store: &Store,
+    servers: &HashMap<String, ServerInfo>,
+    dirty: &mut bool,
+) -> bool {
+    if !*dirty {
+        return true;
+    }
+    match store.persist_servers(servers).await {
+        Ok(()) => *dirty = false,
+        Err(e) => error!("Failed to persist maker registry: {e:?}"),
+    }
+    !*dirty
+}
+
+async fn persist_checkpoint(store: &Store, checkpoint: &Checkpoint) {
+    if let Err(e) = store.persist_checkpoint(checkpoint).await {
+        error!("Failed to persist indexer checkpoint: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{
+        Amount, OutPoint, PublicKey, Txid,
+        absolute::LockTime,
+        hashes::Hash,
+        secp256k1::{Secp256k1, SecretKey},
+    };
+    use tokio::sync::mpsc::{self, Sender};
+
+    use super::*;
+    use crate::types::ProbeHistory;
+
+    /// A datadir of its own for each test, removed once the test is done with it.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("tracker-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Runs a DB manager on `datadir`, returning the sender to talk to it.
+    fn start(datadir: &TempDir) -> Sender<DbRequest> {
+        let (db_tx, db_rx) = mpsc::channel(16);
+        let (status_tx, _) = mpsc::channel(16);
+        tokio::spawn(run(
+            db_rx,
+            status::Sender::DBManager(status_tx),
+            datadir.0.clone(),
+            RankingKind::BondValue,
+        ));
+        db_tx
+    }
+
+    async fn query(db_tx: &Sender<DbRequest>, address: &str) -> Option<ServerInfo> {
+        let (resp_tx, mut resp_rx) = mpsc::channel(1);
+        db_tx
+            .send(DbRequest::Query(address.to_string(), resp_tx))
+            .await
+            .unwrap();
+        resp_rx.recv().await.unwrap()
+    }
+
+    /// An announcement of `address` backed by a bond held by key `n`, found at `found_height` or
+    /// in the mempool if `None`.
+    fn announced(address: &str, n: u8, found_height: Option<u64>) -> ServerInfo {
+        let key = SecretKey::from_slice(&[n; 32]).unwrap();
+        ServerInfo {
+            onion_address: address.to_string(),
+            cooldown: Instant::now(),
+            stale: false,
+            live_since: Instant::now(),
+            last_ping: None,
+            found_height,
+            unconfirmed: found_height.is_none(),
+            bond: FidelityBond {
+                outpoint: OutPoint::new(Txid::from_byte_array([n; 32]), 0),
+                amount: Amount::ONE_BTC,
+                lock_time: LockTime::from_height(500_000).unwrap(),
+                pubkey: PublicKey::new(key.public_key(&Secp256k1::new())),
+                conf_height: found_height.map(|h| h as u32),
+                cert_expiry: None,
+            },
+            pending_bond: None,
+            bond_value: Amount::ZERO,
+            history: ProbeHistory::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_mempool_sighting_of_confirmed_maker() {
+        let datadir = TempDir::new("ignores-mempool-sighting");
+        let db_tx = start(&datadir);
+        let address = "maker.onion:6102";
+        let confirmed = announced(address, 1, Some(10));
+        db_tx
+            .send(DbRequest::Add(address.to_string(), confirmed.clone()))
+            .await
+            .unwrap();
+
+        let sighting = announced(address, 2, None);
+        db_tx
+            .send(DbRequest::Add(address.to_string(), sighting))
+            .await
+            .unwrap();
+        let info = query(&db_tx, address).await.unwrap();
+        assert!(!info.unconfirmed);
+        assert_eq!(info.bond, confirmed.bond);
+
+        // The sighting's transaction leaving the mempool must not take the maker with it.
+        db_tx
+            .send(DbRequest::DropUnconfirmed(address.to_string()))
+            .await
+            .unwrap();
+        let info = query(&db_tx, address).await.unwrap();
+        assert_eq!(info.bond, confirmed.bond);
+        assert_eq!(info.found_height, Some(10));
+    }
+
+    #[tokio::test]
+    async fn confirmation_promotes_mempool_sighting() {
+        let datadir = TempDir::new("promotes-mempool-sighting");
+        let db_tx = start(&datadir);
+        let address = "maker.onion:6102";
+        db_tx
+            .send(DbRequest::Add(
+                address.to_string(),
+                announced(address, 1, None),
+            ))
+            .await
+            .unwrap();
+        assert!(query(&db_tx, address).await.unwrap().unconfirmed);
+
+        let confirmed = announced(address, 1, Some(10));
+        db_tx
+            .send(DbRequest::Add(address.to_string(), confirmed.clone()))
+            .await
+            .unwrap();
+        db_tx
+            .send(DbRequest::DropUnconfirmed(address.to_string()))
+            .await
+            .unwrap();
+        let info = query(&db_tx, address).await.unwrap();
+        assert!(!info.unconfirmed);
+        assert_eq!(info.bond, confirmed.bond);
+    }
+
+    async fn register(
+        db_tx: &Sender<DbRequest>,
+        address: &str,
+        info: ServerInfo,
+    ) -> Result<(), RejectReason> {
+        let (resp_tx, mut resp_rx) = mpsc::channel(1);
+        db_tx
+            .send(DbRequest::Register(address.to_string(), info, resp_tx))
+            .await
+            .unwrap();
+        resp_rx.recv().await.unwrap()
+    }
+
+    async fn record_probe(db_tx: &Sender<DbRequest>, address: &str, rekeyed: bool) {
+        let request = DbRequest::RecordProbe {
+            address: address.to_string(),
+            latency: Some(Duration::from_millis(100)),
+            rekeyed,
+        };
+        db_tx.send(request).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_bond_key_until_new_key_answers_a_probe() {
+        let datadir = TempDir::new("keeps-bond-key");
+        let db_tx = start(&datadir);
+        let address = "maker.onion:6102";
+        let original = announced(address, 1, Some(10));
+        assert_eq!(register(&db_tx, address, original.clone()).await, Ok(()));
+
+        let other = announced(address, 2, Some(20));
+        assert_eq!(
+            register(&db_tx, address, other.clone()).await,
+            Err(RejectReason::AddressTaken)
+        );
+        let info = query(&db_tx, address).await.unwrap();
+        assert_eq!(info.bond, original.bond);
+        assert_eq!(info.pending_bond, Some(other.bond.clone()));
+
+        // Answering with the registered key leaves the pending bond waiting.
+        record_probe(&db_tx, address, false).await;
+        let info = query(&db_tx, address).await.unwrap();
+        assert_eq!(info.bond, original.bond);
+
+        record_probe(&db_tx, address, true).await;
+        let info = query(&db_tx, address).await.unwrap();
+        assert_eq!(info.bond, other.bond);
+        assert_eq!(info.pending_bond, None);
+    }
+
+    #[tokio::test]
+    async fn one_bond_backs_one_address() {
+        let datadir = TempDir::new("one-bond-one-address");
+        let db_tx = start(&datadir);
+        let first = "first.onion:6102";
+        let second = "second.onion:6102";
+        assert_eq!(
+            register(&db_tx, first, announced(first, 1, Some(10))).await,
+            Ok(())
+        );
+        assert_eq!(
+            register(&db_tx, second, announced(second, 1, Some(10))).await,
+            Err(RejectReason::BondInUse)
+        );
+        assert!(query(&db_tx, second).await.is_none());
+
+        // Nor may a bond waiting to replace another be claimed meanwhile.
+        let pending = announced(first, 2, Some(20));
+        assert_eq!(
+            register(&db_tx, first, pending).await,
+            Err(RejectReason::AddressTaken)
+        );
+        assert_eq!(
+            register(&db_tx, second, announced(second, 2, Some(20))).await,
+            Err(RejectReason::BondInUse)
+        );
+    }
+}
@@ -0,0 +1,32 @@
+use std::sync::{Arc, RwLock};
+
+use tokio::sync::mpsc::{Sender, error::SendError};
+
+use crate::types::DbRequest;
+
+/// Sender to the DB manager that stays valid when the DB manager is restarted.
+///
+/// Every clone shares the same slot, so swapping in the sender of a fresh DB manager reroutes
+/// all components at once. The DB manager sees its channel close once every handle is dropped.
+#[derive(Clone)]
+pub struct DbHandle {
+    tx: Arc<RwLock<Sender<DbRequest>>>,
+}
+
+impl DbHandle {
+    pub fn new(tx: Sender<DbRequest>) -> Self {
+        DbHandle {
+            tx: Arc::new(RwLock::new(tx)),
+        }
+    }
+
+    pub async fn send(&self, request: DbRequest) -> Result<(), SendError<DbRequest>> {
+        let tx = self.tx.read().unwrap_or_else(|e| e.into_inner()).clone();
+        tx.send(request).await
+    }
+
+    /// Points every clone of this handle at a new DB manager.
+    pub fn replace(&self, tx: Sender<DbRequest>) {
+        *self.tx.write().unwrap_or_else(|e| e.into_inner()) = tx;
+    }
+}
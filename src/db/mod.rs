@@ -1,2 +1,8 @@
 mod db_manager;
+mod handle;
+mod query;
+mod ranking;
+mod store;
 pub use db_manager::run;
+pub use handle::DbHandle;
+pub use ranking::RankingKind;
@@ -0,0 +1,214 @@
+use std::{cmp::Reverse, collections::HashMap, time::Duration};
+
+use crate::types::{Cursor, MakerFilter, MakerRecord, RecordPage, ServerInfo};
+
+/// Most makers returned in one page, whatever limit the client asks for.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// Makers that can be handed out: confirmed and reachable.
+pub fn active(servers: &HashMap<String, ServerInfo>) -> Vec<(&String, &ServerInfo)> {
+    servers
+        .iter()
+        .filter(|(_, info)| !info.stale && !info.unconfirmed)
+        .collect()
+}
+
+/// Where a maker falls in the order query pages follow: largest bond first, then the latest
+/// locktime, then by address. Unlike bond value, none of it changes as blocks are mined.
+type PageKey<'a> = (Reverse<u64>, Reverse<u32>, &'a str);
+
+fn page_key<'a>(addr: &'a str, info: &ServerInfo) -> PageKey<'a> {
+    (
+        Reverse(info.bond.amount.to_sat()),
+        Reverse(info.bond.lock_time.to_consensus_u32()),
+        addr,
+    )
+}
+
+fn cursor_key(cursor: &Cursor) -> PageKey<'_> {
+    (
+        Reverse(cursor.amount.to_sat()),
+        Reverse(cursor.lock_time.to_consensus_u32()),
+        &cursor.address,
+    )
+}
+
+/// The page of active makers matching `filter`.
+///
+/// Pages follow bond amount and locktime rather than the configured ranking policy or bond value,
+/// which move as blocks are mined, so that a cursor still points at the same place when the next
+/// page is requested.
+pub fn query_filtered(servers: &HashMap<String, ServerInfo>, filter: &MakerFilter) -> RecordPage {
+    let min_uptime = Duration::from_secs(filter.min_uptime.unwrap_or(0));
+    let mut makers: Vec<(&String, &ServerInfo)> = active(servers)
+        .into_iter()
+        .filter(|(_, info)| {
+            filter
+                .min_bond_value
+                .is_none_or(|min| info.bond_value >= min)
+        })
+        .filter(|(_, info)| info.live_since.elapsed() >= min_uptime)
+        .filter(|(addr, _)| !filter.exclude.contains(addr))
+        .filter(|(addr, info)| {
+            filter
+                .cursor
+                .as_ref()
+                .is_none_or(|cursor| page_key(addr, info) > cursor_key(cursor))
+        })
+        .collect();
+    makers.sort_by(|a, b| page_key(a.0, a.1).cmp(&page_key(b.0, b.1)));
+
+    let limit = filter
+        .limit
+        .map_or(MAX_PAGE_SIZE, |limit| (limit as usize).min(MAX_PAGE_SIZE));
+    let next_cursor = (limit > 0 && makers.len() > limit).then(|| {
+        let (addr, info) = makers[limit - 1];
+        Cursor {
+            amount: info.bond.amount,
+            lock_time: info.bond.lock_time,
+            address: addr.clone(),
+        }
+    });
+    makers.truncate(limit);
+
+    RecordPage {
+        records: makers
+            .into_iter()
+            .map(|(addr, info)| MakerRecord::new(addr, info))
+            .collect(),
+        next_cursor,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{
+        Amount, OutPoint, PublicKey,
+        absolute::LockTime,
+        secp256k1::{Secp256k1, SecretKey},
+    };
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::types::{FidelityBond, ProbeHistory};
+
+    /// A maker with a bond of `btc` locked until `lock_height`, up for the last `uptime` seconds.
+    fn maker(btc: u64, lock_height: u32, uptime: u64) -> ServerInfo {
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let amount = Amount::from_btc(btc as f64).unwrap();
+        ServerInfo {
+            onion_address: String::new(),
+            cooldown: Instant::now(),
+            stale: false,
+            live_since: Instant::now() - Duration::from_secs(uptime),
+            last_ping: None,
+            found_height: Some(1),
+            unconfirmed: false,
+            bond: FidelityBond {
+                outpoint: OutPoint::null(),
+                amount,
+                lock_time: LockTime::from_height(lock_height).unwrap(),
+                pubkey: PublicKey::new(key.public_key(&Secp256k1::new())),
+                conf_height: Some(1),
+                cert_expiry: None,
+            },
+            pending_bond: None,
+            bond_value: amount,
+            history: ProbeHistory::default(),
+        }
+    }
+
+    /// Five makers, in page order: e, d, c, a, b.
+    fn registry() -> HashMap<String, ServerInfo> {
+        [
+            ("a", maker(2, 1_000, 60)),
+            ("b", maker(2, 1_000, 3_600)),
+            ("c", maker(2, 2_000, 60)),
+            ("d", maker(5, 1_000, 3_600)),
+            ("e", maker(9, 1_000, 60)),
+        ]
+        .into_iter()
+        .map(|(address, info)| (address.to_string(), info))
+        .collect()
+    }
+
+    fn addresses(page: &RecordPage) -> Vec<&str> {
+        page.records.iter().map(|r| r.address.as_str()).collect()
+    }
+
+    #[test]
+    fn returns_active_makers_in_page_order() {
+        let mut servers = registry();
+        servers.get_mut("d").unwrap().stale = true;
+        servers.get_mut("c").unwrap().unconfirmed = true;
+        let page = query_filtered(&servers, &MakerFilter::default());
+        assert_eq!(addresses(&page), ["e", "a", "b"]);
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[test]
+    fn caps_page_at_limit() {
+        let servers = registry();
+        let filter = MakerFilter {
+            limit: Some(2),
+            ..MakerFilter::default()
+        };
+        let page = query_filtered(&servers, &filter);
+        assert_eq!(addresses(&page), ["e", "d"]);
+        assert!(page.next_cursor.is_some());
+
+        let filter = MakerFilter {
+            limit: Some(5),
+            ..MakerFilter::default()
+        };
+        assert!(query_filtered(&servers, &filter).next_cursor.is_none());
+    }
+
+    #[test]
+    fn filters_by_exclusion_bond_and_uptime() {
+        let servers = registry();
+        let filter = MakerFilter {
+            exclude: vec!["d".to_string(), "a".to_string()],
+            ..MakerFilter::default()
+        };
+        assert_eq!(
+            addresses(&query_filtered(&servers, &filter)),
+            ["e", "c", "b"]
+        );
+
+        let filter = MakerFilter {
+            min_bond_value: Some(Amount::from_btc(5.0).unwrap()),
+            ..MakerFilter::default()
+        };
+        assert_eq!(addresses(&query_filtered(&servers, &filter)), ["e", "d"]);
+
+        let filter = MakerFilter {
+            min_uptime: Some(600),
+            ..MakerFilter::default()
+        };
+        assert_eq!(addresses(&query_filtered(&servers, &filter)), ["d", "b"]);
+    }
+
+    #[test]
+    fn cursor_continues_where_previous_page_ended() {
+        let mut servers = registry();
+        let mut filter = MakerFilter {
+            limit: Some(2),
+            ..MakerFilter::default()
+        };
+        let mut pages = Vec::new();
+        loop {
+            let page = query_filtered(&servers, &filter);
+            pages.push(addresses(&page).join(""));
+            let Some(cursor) = page.next_cursor else {
+                break;
+            };
+            filter.cursor = Some(cursor);
+            // A block is mined between pages, shifting every bond value.
+            for (n, info) in servers.values_mut().enumerate() {
+                info.bond_value = Amount::from_sat(n as u64 * 7 % 5);
+            }
+        }
+        assert_eq!(pages, ["ed", "ca", "b"]);
+    }
+}
@@ -0,0 +1,412 @@
+use std::{
+    cmp::Ordering,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bitcoincore_rpc::bitcoin::{Amount, absolute::LockTime};
+use clap::ValueEnum;
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+use serde::Deserialize;
+
+use crate::types::{FidelityBond, ServerInfo};
+
+/// Yearly interest rate a bond's locked coins are assumed to forgo.
+const INTEREST_RATE: f64 = 0.015;
+/// Makes the value superlinear in the locked amount, so splitting a bond never pays off.
+const BOND_VALUE_EXPONENT: f64 = 1.3;
+/// Average block interval, used to put time-based locktimes on the same scale as heights.
+const BLOCK_INTERVAL_SECS: f64 = 600.0;
+const BLOCKS_PER_YEAR: f64 = 365.2425 * 24.0 * 60.0 * 60.0 / BLOCK_INTERVAL_SECS;
+
+/// Value of a fidelity bond at the given chain height, following the JoinMarket formula:
+///
+/// `(amount * (exp(r * T) - 1) * exp(-r * max(0, t - L))) ^ 1.3`
+///
+/// where `T` is the time the coins are locked for counted from confirmation, `L` the locktime
+/// and `t` now. A bond locked longer, or confirmed earlier, is worth more; once the locktime
+/// has passed its value decays.
+pub fn bond_value(bond: &FidelityBond, tip_height: u64) -> Amount {
+    let tip = tip_height as f64;
+    let conf = bond.conf_height.map_or(tip, f64::from);
+    let locktime = locktime_height(bond.lock_time, tip);
+
+    let lock_years = (locktime - conf).max(0.0) / BLOCKS_PER_YEAR;
+    let expired_years = (tip - locktime).max(0.0) / BLOCKS_PER_YEAR;
+
+    let value = bond.amount.to_sat() as f64
+        * (INTEREST_RATE * lock_years).exp_m1()
+        * (-INTEREST_RATE * expired_years).exp();
+    Amount::from_sat(value.powf(BOND_VALUE_EXPONENT) as u64)
+}
+
+/// Height at which `lock_time` expires, estimated from the current time for time-based locks.
+fn locktime_height(lock_time: LockTime, tip: f64) -> f64 {
+    match lock_time {
+        LockTime::Blocks(height) => f64::from(height.to_consensus_u32()),
+        LockTime::Seconds(time) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |d| d.as_secs_f64());
+            tip + (f64::from(time.to_consensus_u32()) - now) / BLOCK_INTERVAL_SECS
+        }
+    }
+}
+
+/// Decides the order in which active makers are handed out to takers.
+pub trait RankingPolicy: Send {
+    /// Orders `makers`, best first.
+    fn rank(&self, makers: Vec<(&String, &ServerInfo)>) -> Vec<String>;
+}
+
+/// The built-in ranking policies, selectable from the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RankingKind {
+    /// Highest bond value first.
+    BondValue,
+    /// Random order, where a maker's chance to come early is proportional to its bond value.
+    WeightedRandom,
+    /// Uniformly random order.
+    Random,
+    /// Highest share of recent probes answered first, then highest bond value.
+    Uptime,
+    /// Lowest median probe latency first, then highest bond value. Makers not yet probed come last.
+    Latency,
+}
+
+impl RankingKind {
+    pub fn policy(self) -> Box<dyn RankingPolicy> {
+        match self {
+            RankingKind::BondValue => Box::new(BondValue),
+            RankingKind::WeightedRandom => Box::new(WeightedRandom),
+            RankingKind::Random => Box::new(Random),
+            RankingKind::Uptime => Box::new(Uptime),
+            RankingKind::Latency => Box::new(Latency),
+        }
+    }
+}
+
+pub struct BondValue;
+
+impl RankingPolicy for BondValue {
+    fn rank(&self, mut makers: Vec<(&String, &ServerInfo)>) -> Vec<String> {
+        makers.sort_by(|a, b| by_bond_value((a.0, a.1.bond_value), (b.0, b.1.bond_value)));
+        addresses(makers)
+    }
+}
+
+/// Highest bond value first, ties broken by address so the order is total.
+pub fn by_bond_value(a: (&String, Amount), b: (&String, Amount)) -> Ordering {
+    b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0))
+}
+
+pub struct WeightedRandom;
+
+impl RankingPolicy for WeightedRandom {
+    fn rank(&self, makers: Vec<(&String, &ServerInfo)>) -> Vec<String> {
+        self.rank_with(makers, &mut rand::thread_rng())
+    }
+}
+
+impl WeightedRandom {
+    fn rank_with<R: Rng>(&self, makers: Vec<(&String, &ServerInfo)>, rng: &mut R) -> Vec<String> {
+        addresses(weighted_shuffle(makers, rng))
+    }
+}
+
+pub struct Random;
+
+impl RankingPolicy for Random {
+    fn rank(&self, makers: Vec<(&String, &ServerInfo)>) -> Vec<String> {
+        self.rank_with(makers, &mut rand::thread_rng())
+    }
+}
+
+impl Random {
+    fn rank_with<R: Rng>(
+        &self,
+        mut makers: Vec<(&String, &ServerInfo)>,
+        rng: &mut R,
+    ) -> Vec<String> {
+        makers.shuffle(rng);
+        addresses(makers)
+    }
+}
+
+pub struct Uptime;
+
+impl RankingPolicy for Uptime {
+    fn rank(&self, mut makers: Vec<(&String, &ServerInfo)>) -> Vec<String> {
+        makers.sort_by(|a, b| {
+            let uptime = |info: &ServerInfo| info.history.uptime().unwrap_or(0.0);
+            uptime(b.1)
+                .total_cmp(&uptime(a.1))
+                .then_with(|| by_bond_value((a.0, a.1.bond_value), (b.0, b.1.bond_value)))
+        });
+        addresses(makers)
+    }
+}
+
+pub struct Latency;
+
+impl RankingPolicy for Latency {
+    fn rank(&self, mut makers: Vec<(&String, &ServerInfo)>) -> Vec<String> {
+        makers.sort_by(|a, b| {
+            let latency =
+                |info: &ServerInfo| info.history.median_latency().unwrap_or(Duration::MAX);
+            latency(a.1)
+                .cmp(&latency(b.1))
+                .then_with(|| by_bond_value((a.0, a.1.bond_value), (b.0, b.1.bond_value)))
+        });
+        addresses(makers)
+    }
+}
+
+/// Shuffles makers so that each one's chance of coming before another is proportional to its
+/// bond value (Efraimidis-Spirakis: sort by `u^(1/w)` for uniform `u`, done in log space).
+pub fn weighted_shuffle<'a, R: Rng>(
+    makers: Vec<(&'a String, &'a ServerInfo)>,
+    rng: &mut R,
+) -> Vec<(&'a String, &'a ServerInfo)> {
+    let mut keyed: Vec<_> = makers
+        .into_iter()
+        .map(|maker| {
+            // A worthless bond still gets a token weight rather than never being picked.
+            let weight = maker.1.bond_value.to_sat().max(1) as f64;
+            let key = rng.r#gen::<f64>().ln() / weight;
+            (key, maker)
+        })
+        .collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed.into_iter().map(|(_, maker)| maker).collect()
+}
+
+/// A random sample of up to `count` makers, each pick weighted by bond value.
+///
+/// The same seed and makers always give the same sample, which makes it reproducible in tests.
+pub fn weighted_sample<'a>(
+    mut makers: Vec<(&'a String, &'a ServerInfo)>,
+    count: usize,
+    seed: Option<u64>,
+) -> Vec<(&'a String, &'a ServerInfo)> {
+    // The registry's iteration order is itself random, so fix it for the seed to mean anything.
+    makers.sort_by(|a, b| a.0.cmp(b.0));
+    let mut sample = match seed {
+        Some(seed) => weighted_shuffle(makers, &mut StdRng::seed_from_u64(seed)),
+        None => weighted_shuffle(makers, &mut rand::thread_rng()),
+    };
+    sample.truncate(count);
+    sample
+}
+
+fn addresses(makers: Vec<(&String, &ServerInfo)>) -> Vec<String> {
+    makers.into_iter().map(|(addr, _)| addr.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bitcoincore_rpc::bitcoin::{
+        OutPoint, PublicKey,
+        secp256k1::{Secp256k1, SecretKey},
+    };
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::types::ProbeHistory;
+
+    /// A maker with the given bond value whose probes took the given milliseconds, or failed.
+    fn maker(bond_value: u64, probes: &[Option<u64>]) -> ServerInfo {
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let mut history = ProbeHistory::default();
+        for probe in probes {
+            history.record(probe.map(Duration::from_millis));
+        }
+        ServerInfo {
+            onion_address: String::new(),
+            cooldown: Instant::now(),
+            stale: false,
+            live_since: Instant::now(),
+            last_ping: None,
+            found_height: Some(1),
+            unconfirmed: false,
+            bond: FidelityBond {
+                outpoint: OutPoint::null(),
+                amount: Amount::ONE_BTC,
+                lock_time: LockTime::ZERO,
+                pubkey: PublicKey::new(key.public_key(&Secp256k1::new())),
+                conf_height: Some(1),
+                cert_expiry: None,
+            },
+            pending_bond: None,
+            bond_value: Amount::from_sat(bond_value),
+            history,
+        }
+    }
+
+    fn fixture(makers: &[(&str, ServerInfo)]) -> Vec<(String, ServerInfo)> {
+        makers
+            .iter()
+            .map(|(address, info)| (address.to_string(), info.clone()))
+            .collect()
+    }
+
+    fn refs(makers: &[(String, ServerInfo)]) -> Vec<(&String, &ServerInfo)> {
+        makers
+            .iter()
+            .map(|(address, info)| (address, info))
+            .collect()
+    }
+
+    /// How often each maker is ranked first over `trials` rankings.
+    fn first_place_shares(
+        makers: &[(String, ServerInfo)],
+        trials: usize,
+        mut rank: impl FnMut() -> Vec<String>,
+    ) -> HashMap<String, f64> {
+        let mut firsts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..trials {
+            let ranked = rank();
+            assert_eq!(ranked.len(), makers.len());
+            *firsts.entry(ranked[0].clone()).or_default() += 1;
+        }
+        firsts
+            .into_iter()
+            .map(|(address, count)| (address, count as f64 / trials as f64))
+            .collect()
+    }
+
+    #[test]
+    fn bond_value_ranks_highest_first_with_ties_by_address() {
+        let makers = fixture(&[
+            ("d", maker(200, &[])),
+            ("c", maker(300, &[])),
+            ("a", maker(100, &[])),
+            ("b", maker(300, &[])),
+        ]);
+        assert_eq!(BondValue.rank(refs(&makers)), ["b", "c", "d", "a"]);
+    }
+
+    #[test]
+    fn uptime_ranks_most_answered_first_with_ties_by_bond_value() {
+        let makers = fixture(&[
+            ("flaky", maker(1_000, &[Some(10), None])),
+            ("unprobed", maker(5_000, &[])),
+            ("small", maker(10, &[Some(10), Some(10)])),
+            ("big", maker(50, &[Some(10)])),
+            ("down", maker(9_000, &[None, None])),
+        ]);
+        assert_eq!(
+            Uptime.rank(refs(&makers)),
+            ["big", "small", "flaky", "down", "unprobed"]
+        );
+    }
+
+    #[test]
+    fn latency_ranks_fastest_first_with_ties_by_bond_value() {
+        let makers = fixture(&[
+            ("slow", maker(1_000, &[Some(200), Some(300)])),
+            ("unprobed", maker(5_000, &[])),
+            ("fast_small", maker(10, &[Some(40), Some(50), Some(900)])),
+            ("fast_big", maker(20, &[Some(50), None])),
+            ("down", maker(100, &[None])),
+        ]);
+        assert_eq!(
+            Latency.rank(refs(&makers)),
+            ["fast_big", "fast_small", "slow", "unprobed", "down"]
+        );
+    }
+
+    #[test]
+    fn weighted_random_favours_bond_value() {
+        let makers = fixture(&[
+            ("heavy", maker(600, &[])),
+            ("medium", maker(300, &[])),
+            ("light", maker(100, &[])),
+        ]);
+        let mut rng = StdRng::seed_from_u64(7);
+        let shares = first_place_shares(&makers, 10_000, || {
+            WeightedRandom.rank_with(refs(&makers), &mut rng)
+        });
+        // Each maker comes first in proportion to its share of the total bond value.
+        for (address, expected) in [("heavy", 0.6), ("medium", 0.3), ("light", 0.1)] {
+            let share = shares[address];
+            assert!((share - expected).abs() < 0.03, "{address}: {share}");
+        }
+    }
+
+    #[test]
+    fn random_ignores_bond_value() {
+        let makers = fixture(&[
+            ("heavy", maker(1_000_000, &[])),
+            ("medium", maker(1_000, &[])),
+            ("light", maker(1, &[])),
+        ]);
+        let mut rng = StdRng::seed_from_u64(7);
+        let shares =
+            first_place_shares(&makers, 9_000, || Random.rank_with(refs(&makers), &mut rng));
+        for address in ["heavy", "medium", "light"] {
+            let share = shares[address];
+            assert!((share - 1.0 / 3.0).abs() < 0.03, "{address}: {share}");
+        }
+    }
+
+    fn sample_addresses(makers: &[(String, ServerInfo)], count: usize, seed: u64) -> Vec<String> {
+        addresses(weighted_sample(refs(makers), count, Some(seed)))
+    }
+
+    fn ten_makers() -> Vec<(String, ServerInfo)> {
+        (1..=10)
+            .map(|i| (format!("maker{i:02}"), maker(i * 100, &[])))
+            .collect()
+    }
+
+    #[test]
+    fn weighted_sample_is_reproducible_from_seed() {
+        let makers = ten_makers();
+        let mut reversed = makers.clone();
+        reversed.reverse();
+        let sample = sample_addresses(&makers, 5, 42);
+        assert_eq!(sample_addresses(&makers, 5, 42), sample);
+        // The registry hands makers over in no particular order, which must not matter.
+        assert_eq!(sample_addresses(&reversed, 5, 42), sample);
+        assert!((0..10).any(|seed| sample_addresses(&makers, 5, seed) != sample));
+    }
+
+    #[test]
+    fn weighted_sample_returns_at_most_count() {
+        let makers = ten_makers();
+        assert_eq!(sample_addresses(&makers, 3, 1).len(), 3);
+        assert_eq!(sample_addresses(&makers, 0, 1).len(), 0);
+        let mut all = sample_addresses(&makers, 20, 1);
+        all.sort();
+        let mut expected: Vec<_> = makers.iter().map(|(address, _)| address.clone()).collect();
+        expected.sort();
+        assert_eq!(all, expected);
+    }
+
+    #[test]
+    fn weighted_sample_favours_bond_value() {
+        let makers = fixture(&[
+            ("heavy", maker(4_000, &[])),
+            ("medium", maker(2_000, &[])),
+            ("light", maker(1_000, &[])),
+            ("tiny", maker(100, &[])),
+        ]);
+        let mut picks: HashMap<String, usize> = HashMap::new();
+        for seed in 0..5_000 {
+            for address in sample_addresses(&makers, 2, seed) {
+                *picks.entry(address).or_default() += 1;
+            }
+        }
+        let picks = |address: &str| picks.get(address).copied().unwrap_or(0);
+        assert!(picks("heavy") > picks("medium"));
+        assert!(picks("medium") > picks("light"));
+        assert!(picks("light") > picks("tiny"));
+        // Holding 56% of the total bond value, the heaviest maker makes it into about 88% of the
+        // samples of two.
+        let share = picks("heavy") as f64 / 5_000.0;
+        assert!((share - 0.88).abs() < 0.03, "{share}");
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use bitcoincore_rpc::bitcoin::Amount;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, time::Instant};
+
+use crate::{
+    error::TrackerError,
+    types::{Checkpoint, FidelityBond, ProbeHistory, ServerInfo},
+    utils::{from_unix, to_unix},
+};
+
+const DB_DIR: &str = "db";
+const SERVERS_FILE: &str = "servers.cbor";
+const CHECKPOINT_FILE: &str = "checkpoint.cbor";
+
+/// On-disk representation of a [`ServerInfo`].
+///
+/// `Instant` is not meaningful across restarts, so times are stored as unix timestamps.
+#[derive(Serialize, Deserialize)]
+struct StoredServer {
+    onion_address: String,
+    cooldown: u64,
+    stale: bool,
+    /// Missing in entries written before uptime was tracked, which count as live from load.
+    #[serde(default)]
+    live_since: Option<u64>,
+    #[serde(default)]
+    last_ping: Option<u64>,
+    #[serde(default)]
+    history: ProbeHistory,
+    #[serde(default)]
+    found_height: Option<u64>,
+    #[serde(default)]
+    unconfirmed: bool,
+    /// Entries indexed before bonds were checked have none and are dropped on load.
+    #[serde(default)]
+    bond: Option<FidelityBond>,
+    #[serde(default)]
+    pending_bond: Option<FidelityBond>,
+}
+
+impl From<&ServerInfo> for StoredServer {
+    fn from(info: &ServerInfo) -> Self {
+        StoredServer {
+            onion_address: info.onion_address.clone(),
+            cooldown: to_unix(info.cooldown),
+            stale: info.stale,
+            live_since: Some(to_unix(info.live_since)),
+            last_ping: info.last_ping.map(to_unix),
+            history: info.history.clone(),
+            found_height: info.found_height,
+            unconfirmed: info.unconfirmed,
+            bond: Some(info.bond.clone()),
+            pending_bond: info.pending_bond.clone(),
+        }
+    }
+}
+
+impl StoredServer {
+    fn into_server_info(self) -> Option<ServerInfo> {
+        Some(ServerInfo {
+            onion_address: self.onion_address,
+            cooldown: from_unix(self.cooldown),
+            stale: self.stale,
+            live_since: self.live_since.map_or_else(Instant::now, from_unix),
+            last_ping: self.last_ping.map(from_unix),
+            found_height: self.found_height,
+            unconfirmed: self.unconfirmed,
+            bond: self.bond?,
+            pending_bond: self.pending_bond,
+            bond_value: Amount::ZERO,
+            history: self.history,
+        })
+    }
+}
+
+/// Durable snapshot of the maker registry and indexer checkpoint, kept under the tracker datadir.
+///
+/// The checkpoint lives in its own file and is only written after the entries of the blocks it
+/// covers, so a crash in between at worst makes the indexer re-scan a few blocks.
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn new(datadir: &Path) -> Self {
+        Store {
+            dir: datadir.join(DB_DIR),
+        }
+    }
+
+    /// Loads the registry, returning an empty one if nothing has been persisted yet.
+    ///
+    /// Also returns how many entries were dropped for lacking a fidelity bond.
+    pub async fn load_servers(&self) -> Result<(HashMap<String, ServerInfo>, usize), TrackerError> {
+        let Some(bytes) = self.read(SERVERS_FILE).await? else {
+            return Ok((HashMap::new(), 0));
+        };
+        let stored: HashMap<String, StoredServer> = serde_cbor::de::from_slice(&bytes)?;
+        let total = stored.len();
+        let servers: HashMap<String, ServerInfo> = stored
+            .into_iter()
+            .filter_map(|(addr, server)| Some((addr, server.into_server_info()?)))
+            .collect();
+        let dropped = total - servers.len();
+        Ok((servers, dropped))
+    }
+
+    pub async fn load_checkpoint(&self) -> Result<Option<Checkpoint>, TrackerError> {
+        match self.read(CHECKPOINT_FILE).await? {
+            Some(bytes) => Ok(Some(serde_cbor::de::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn persist_servers(
+        &self,
+        servers: &HashMap<String, ServerInfo>,
+    ) -> Result<(), TrackerError> {
+        let stored: HashMap<&String, StoredServer> = servers
+            .iter()
+            .map(|(addr, info)| (addr, info.into()))
+            .collect();
+        self.write(SERVERS_FILE, &serde_cbor::ser::to_vec(&stored)?)
+            .await
+    }
+
+    pub async fn persist_checkpoint(&self, checkpoint: &Checkpoint) -> Result<(), TrackerError> {
+        self.write(CHECKPOINT_FILE, &serde_cbor::ser::to_vec(checkpoint)?)
+            .await
+    }
+
+    async fn read(&self, name: &str) -> Result<Option<Vec<u8>>, TrackerError> {
+        let path = self.dir.join(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(&path).await?))
+    }
+
+    /// Writes to a temporary file which is synced and then renamed over the old one, so a crash
+    /// mid-write leaves either the previous or the new contents, never a torn file.
+    async fn write(&self, name: &str, bytes: &[u8]) -> Result<(), TrackerError> {
+        fs::create_dir_all(&self.dir).await?;
+
+        let path = self.dir.join(name);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path).await?;
+        file.write_all(bytes).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        fs::rename(&tmp_path, &path).await?;
+        fs::File::open(&self.dir).await?.sync_all().await?;
+        Ok(())
+    }
+}
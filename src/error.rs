@@ -1,4 +1,4 @@
-use crate::DbRequest;
+use crate::types::DbRequest;
 use std::error::Error;
 
 #[derive(Debug)]
@@ -0,0 +1,381 @@
+use bitcoincore_rpc::bitcoin::{
+    OutPoint, PublicKey, ScriptBuf, Transaction,
+    absolute::{Height, LockTime},
+    blockdata::{
+        opcodes::all::{OP_CHECKSIG, OP_CLTV, OP_DROP, OP_RETURN},
+        script::{Builder, Instruction},
+    },
+    consensus::Encodable,
+    hashes::{Hash, HashEngine, hash160, sha256},
+    secp256k1::{Message, Secp256k1, ecdsa::Signature},
+};
+
+use crate::{
+    types::{FidelityBond, FidelityProof, RejectReason},
+    utils::is_valid_onion_address,
+};
+
+/// Length of a difficulty adjustment period, the unit `cert_expiry` is counted in.
+pub const DIFFICULTY_PERIOD: u64 = 2016;
+
+const CERT_TAG: &[u8] = b"fidelity-bond-cert";
+const HEARTBEAT_TAG: &[u8] = b"tracker-heartbeat";
+const PING_TAG: &[u8] = b"tracker-ping";
+
+/// How far a heartbeat's timestamp may be from the tracker's clock, limiting how long a captured
+/// heartbeat can be replayed.
+pub const HEARTBEAT_MAX_SKEW: u64 = 5 * 60;
+
+/// Witness script locking a fidelity bond: `<locktime> OP_CLTV OP_DROP <pubkey> OP_CHECKSIG`.
+pub fn fidelity_redeemscript(lock_time: LockTime, pubkey: &PublicKey) -> ScriptBuf {
+    Builder::new()
+        .push_lock_time(lock_time)
+        .push_opcode(OP_CLTV)
+        .push_opcode(OP_DROP)
+        .push_key(pubkey)
+        .push_opcode(OP_CHECKSIG)
+        .into_script()
+}
+
+/// The P2WSH output script a bond with the given locktime and pubkey pays to.
+pub fn fidelity_script_pubkey(lock_time: LockTime, pubkey: &PublicKey) -> ScriptBuf {
+    ScriptBuf::new_p2wsh(&fidelity_redeemscript(lock_time, pubkey).wscript_hash())
+}
+
+/// Finds the fidelity bond in a maker announcement, along with the address it advertises.
+///
+/// An announcement has an `OP_RETURN <onion:port> <pubkey> <locktime>` output next to a P2WSH
+/// output paying to [`fidelity_redeemscript`]. The P2WSH only commits to a hash of the script, so
+/// the OP_RETURN is what lets us rebuild and check it. The locktime is carried there rather than
+/// in the transaction's own `nLockTime`, which would keep the announcement from confirming until
+/// the bond expires.
+pub fn find_fidelity_bond(tx: &Transaction) -> Option<(String, FidelityBond)> {
+    let (onion_address, pubkey, lock_time) = tx
+        .output
+        .iter()
+        .find_map(|txout| parse_announcement(&txout.script_pubkey))?;
+
+    let script_pubkey = fidelity_script_pubkey(lock_time, &pubkey);
+    let (vout, txout) = tx
+        .output
+        .iter()
+        .enumerate()
+        .find(|(_, txout)| txout.script_pubkey == script_pubkey)?;
+
+    let bond = FidelityBond {
+        outpoint: OutPoint::new(tx.compute_txid(), vout as u32),
+        amount: txout.value,
+        lock_time,
+        pubkey,
+        conf_height: None,
+        cert_expiry: None,
+    };
+    Some((onion_address, bond))
+}
+
+/// Parses `OP_RETURN <onion:port> <pubkey> <locktime>`, the locktime being its 4-byte
+/// little-endian consensus encoding.
+fn parse_announcement(script: &ScriptBuf) -> Option<(String, PublicKey, LockTime)> {
+    if !script.is_op_return() {
+        return None;
+    }
+    let mut instructions = script.instructions();
+    if instructions.next()?.ok()? != Instruction::Op(OP_RETURN) {
+        return None;
+    }
+    let Instruction::PushBytes(address) = instructions.next()?.ok()? else {
+        return None;
+    };
+    let Instruction::PushBytes(pubkey) = instructions.next()?.ok()? else {
+        return None;
+    };
+    let Instruction::PushBytes(lock_time) = instructions.next()?.ok()? else {
+        return None;
+    };
+    if instructions.next().is_some() {
+        return None;
+    }
+
+    let onion_address = String::from_utf8(address.as_bytes().to_vec()).ok()?;
+    if !is_valid_onion_address(&onion_address) {
+        return None;
+    }
+    let pubkey = PublicKey::from_slice(pubkey.as_bytes()).ok()?;
+    let lock_time =
+        LockTime::from_consensus(u32::from_le_bytes(lock_time.as_bytes().try_into().ok()?));
+    if !is_locked(lock_time) {
+        return None;
+    }
+    Some((onion_address, pubkey, lock_time))
+}
+
+/// Whether a bond with `lock_time` is locked at all. One that never was is worth nothing.
+pub fn is_locked(lock_time: LockTime) -> bool {
+    lock_time != LockTime::Blocks(Height::ZERO)
+}
+
+/// The hash a maker signs with its bond key to vouch for `url` until the bond's `cert_expiry`.
+///
+/// Commits to the bond outpoint and pubkey as well, so a certificate cannot be replayed for
+/// another bond or another address.
+pub fn cert_hash(bond: &FidelityBond, url: &str) -> hash160::Hash {
+    let mut engine = hash160::Hash::engine();
+    engine.input(CERT_TAG);
+    bond.outpoint
+        .consensus_encode(&mut engine)
+        .expect("engines don't error");
+    engine.input(&bond.pubkey.to_bytes());
+    engine.input(&bond.cert_expiry.unwrap_or(0).to_le_bytes());
+    engine.input(url.as_bytes());
+    hash160::Hash::from_engine(engine)
+}
+
+/// Checks the certificate in `proof` for `url` at the given chain height.
+///
+/// The signature is an ECDSA signature by the bond pubkey over `sha256(cert_hash)`. Whether the
+/// bond itself exists on chain is up to the caller.
+pub fn verify_fidelity_proof(
+    proof: &FidelityProof,
+    url: &str,
+    tip_height: u64,
+) -> Result<(), RejectReason> {
+    if !is_valid_onion_address(url) {
+        return Err(RejectReason::InvalidAddress);
+    }
+    if !is_locked(proof.bond.lock_time) {
+        return Err(RejectReason::BondNotLocked);
+    }
+    let Some(cert_expiry) = proof.bond.cert_expiry else {
+        return Err(RejectReason::CertExpired);
+    };
+    if u64::from(cert_expiry) * DIFFICULTY_PERIOD <= tip_height {
+        return Err(RejectReason::CertExpired);
+    }
+    if cert_hash(&proof.bond, url) != proof.cert_hash {
+        return Err(RejectReason::CertHashMismatch);
+    }
+
+    let digest = sha256::Hash::hash(proof.cert_hash.as_byte_array());
+    let message = Message::from_digest(digest.to_byte_array());
+    Secp256k1::verification_only()
+        .verify_ecdsa(&message, &proof.cert_sig, &proof.bond.pubkey.inner)
+        .map_err(|_| RejectReason::InvalidSignature)
+}
+
+/// The hash a maker signs with its bond key to report that `address` is up at `timestamp`.
+pub fn heartbeat_hash(address: &str, timestamp: u64) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(HEARTBEAT_TAG);
+    engine.input(&timestamp.to_le_bytes());
+    engine.input(address.as_bytes());
+    sha256::Hash::from_engine(engine)
+}
+
+/// Checks a heartbeat from the maker holding `pubkey`, made at `timestamp`, against the clock
+/// reading `now`.
+pub fn verify_heartbeat(
+    pubkey: &PublicKey,
+    address: &str,
+    timestamp: u64,
+    signature: &Signature,
+    now: u64,
+) -> Result<(), RejectReason> {
+    if now.abs_diff(timestamp) > HEARTBEAT_MAX_SKEW {
+        return Err(RejectReason::StaleTimestamp);
+    }
+    let message = Message::from_digest(heartbeat_hash(address, timestamp).to_byte_array());
+    Secp256k1::verification_only()
+        .verify_ecdsa(&message, signature, &pubkey.inner)
+        .map_err(|_| RejectReason::InvalidSignature)
+}
+
+/// The hash a maker signs with its bond key to answer a ping carrying `nonce`, sent to `address`.
+pub fn ping_hash(nonce: &[u8; 32], address: &str) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(PING_TAG);
+    engine.input(nonce);
+    engine.input(address.as_bytes());
+    sha256::Hash::from_engine(engine)
+}
+
+/// Checks the signature in a maker's answer to a ping carrying `nonce`, sent to `address`.
+pub fn verify_pong(
+    pubkey: &PublicKey,
+    nonce: &[u8; 32],
+    address: &str,
+    signature: &Signature,
+) -> Result<(), RejectReason> {
+    let message = Message::from_digest(ping_hash(nonce, address).to_byte_array());
+    Secp256k1::verification_only()
+        .verify_ecdsa(&message, signature, &pubkey.inner)
+        .map_err(|_| RejectReason::InvalidSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{Amount, Txid, secp256k1::SecretKey};
+
+    use super::*;
+    use crate::indexer::fake_chain::{maker_announcement, maker_proof};
+
+    const URL: &str = "maker.onion:6102";
+
+    fn key(n: u8) -> (SecretKey, PublicKey) {
+        let key = SecretKey::from_slice(&[n; 32]).unwrap();
+        (key, PublicKey::new(key.public_key(&Secp256k1::new())))
+    }
+
+    fn sign(key: &SecretKey, hash: sha256::Hash) -> Signature {
+        Secp256k1::new().sign_ecdsa(&Message::from_digest(hash.to_byte_array()), key)
+    }
+
+    /// A bond held by key `n` whose certificate expires after ten difficulty periods.
+    fn bond(n: u8) -> FidelityBond {
+        FidelityBond {
+            outpoint: OutPoint::new(Txid::from_byte_array([n; 32]), 0),
+            amount: Amount::ONE_BTC,
+            lock_time: LockTime::from_height(500_000).unwrap(),
+            pubkey: key(n).1,
+            conf_height: Some(100),
+            cert_expiry: Some(10),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_proof() {
+        let proof = maker_proof(bond(1), URL, &key(1).0);
+        assert_eq!(verify_fidelity_proof(&proof, URL, 1_000), Ok(()));
+    }
+
+    #[test]
+    fn rejects_proof_signed_by_another_key() {
+        let proof = maker_proof(bond(1), URL, &key(2).0);
+        assert_eq!(
+            verify_fidelity_proof(&proof, URL, 1_000),
+            Err(RejectReason::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_cert_bound_to_another_url_or_bond() {
+        let proof = maker_proof(bond(1), URL, &key(1).0);
+        assert_eq!(
+            verify_fidelity_proof(&proof, "other.onion:6102", 1_000),
+            Err(RejectReason::CertHashMismatch)
+        );
+
+        let mut moved = proof.clone();
+        moved.bond.outpoint.vout = 1;
+        assert_eq!(
+            verify_fidelity_proof(&moved, URL, 1_000),
+            Err(RejectReason::CertHashMismatch)
+        );
+
+        let mut rekeyed = proof;
+        rekeyed.bond.pubkey = key(2).1;
+        assert_eq!(
+            verify_fidelity_proof(&rekeyed, URL, 1_000),
+            Err(RejectReason::CertHashMismatch)
+        );
+    }
+
+    #[test]
+    fn rejects_expired_or_missing_cert_expiry() {
+        let proof = maker_proof(bond(1), URL, &key(1).0);
+        let expiry = 10 * DIFFICULTY_PERIOD;
+        assert_eq!(verify_fidelity_proof(&proof, URL, expiry - 1), Ok(()));
+        assert_eq!(
+            verify_fidelity_proof(&proof, URL, expiry),
+            Err(RejectReason::CertExpired)
+        );
+
+        let unbounded = FidelityBond {
+            cert_expiry: None,
+            ..bond(1)
+        };
+        let proof = maker_proof(unbounded, URL, &key(1).0);
+        assert_eq!(
+            verify_fidelity_proof(&proof, URL, 0),
+            Err(RejectReason::CertExpired)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_address_and_unlocked_bond() {
+        let proof = maker_proof(bond(1), "maker.example:6102", &key(1).0);
+        assert_eq!(
+            verify_fidelity_proof(&proof, "maker.example:6102", 1_000),
+            Err(RejectReason::InvalidAddress)
+        );
+
+        let unlocked = FidelityBond {
+            lock_time: LockTime::ZERO,
+            ..bond(1)
+        };
+        let proof = maker_proof(unlocked, URL, &key(1).0);
+        assert_eq!(
+            verify_fidelity_proof(&proof, URL, 1_000),
+            Err(RejectReason::BondNotLocked)
+        );
+    }
+
+    #[test]
+    fn finds_bond_in_announcement_unless_unlocked() {
+        let (_, pubkey) = key(1);
+        let funding = OutPoint::new(Txid::from_byte_array([9; 32]), 0);
+        let lock_time = LockTime::from_height(500_000).unwrap();
+        let tx = maker_announcement(funding, URL, &pubkey, Amount::ONE_BTC, lock_time);
+        let (address, bond) = find_fidelity_bond(&tx).unwrap();
+        assert_eq!(address, URL);
+        assert_eq!(bond.outpoint, OutPoint::new(tx.compute_txid(), 0));
+        assert_eq!(bond.amount, Amount::ONE_BTC);
+        assert_eq!(bond.lock_time, lock_time);
+        assert_eq!(bond.pubkey, pubkey);
+
+        let tx = maker_announcement(funding, URL, &pubkey, Amount::ONE_BTC, LockTime::ZERO);
+        assert!(find_fidelity_bond(&tx).is_none());
+    }
+
+    #[test]
+    fn verifies_heartbeat_within_skew() {
+        let (secret, pubkey) = key(1);
+        let now = 1_700_000_000;
+        let signature = sign(&secret, heartbeat_hash(URL, now));
+        assert_eq!(
+            verify_heartbeat(&pubkey, URL, now, &signature, now + HEARTBEAT_MAX_SKEW),
+            Ok(())
+        );
+        assert_eq!(
+            verify_heartbeat(&pubkey, URL, now, &signature, now + HEARTBEAT_MAX_SKEW + 1),
+            Err(RejectReason::StaleTimestamp)
+        );
+        assert_eq!(
+            verify_heartbeat(&key(2).1, URL, now, &signature, now),
+            Err(RejectReason::InvalidSignature)
+        );
+        assert_eq!(
+            verify_heartbeat(&pubkey, "other.onion:6102", now, &signature, now),
+            Err(RejectReason::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn verifies_pong_for_its_nonce_and_address() {
+        let (secret, pubkey) = key(1);
+        let nonce = [7; 32];
+        let signature = sign(&secret, ping_hash(&nonce, URL));
+        assert_eq!(verify_pong(&pubkey, &nonce, URL, &signature), Ok(()));
+        assert_eq!(
+            verify_pong(&pubkey, &[8; 32], URL, &signature),
+            Err(RejectReason::InvalidSignature)
+        );
+        assert_eq!(
+            verify_pong(&pubkey, &nonce, "other.onion:6102", &signature),
+            Err(RejectReason::InvalidSignature)
+        );
+        assert_eq!(
+            verify_pong(&key(2).1, &nonce, URL, &signature),
+            Err(RejectReason::InvalidSignature)
+        );
+    }
+}
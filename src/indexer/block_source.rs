@@ -0,0 +1,170 @@
+use std::ops::RangeInclusive;
+
+use bitcoincore_rpc::bitcoin::{Amount, Block, BlockHash, OutPoint, ScriptBuf, Transaction, Txid};
+use tokio_graceful::{ShutdownGuard, WeakShutdownGuard};
+
+use crate::error::TrackerError;
+
+/// A confirmed, unspent output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    pub value: Amount,
+    pub script_pubkey: ScriptBuf,
+    /// Confirmations at the current tip, 1 for an output mined in the tip block.
+    pub confirmations: u32,
+}
+
+/// Where the indexer gets the chain from: bitcoind in production, an in-memory fake chain in
+/// tests.
+pub trait BlockSource: Send + Sync {
+    /// Height of the active chain's tip.
+    fn tip_height(&self) -> impl Future<Output = Result<u64, TrackerError>> + Send;
+
+    /// Hash of the active chain's block at `height`.
+    fn block_hash(
+        &self,
+        height: u64,
+    ) -> impl Future<Output = Result<BlockHash, TrackerError>> + Send;
+
+    /// Hashes of the active chain's blocks at `heights`, in order.
+    fn block_hashes(
+        &self,
+        heights: RangeInclusive<u64>,
+    ) -> impl Future<Output = Result<Vec<BlockHash>, TrackerError>> + Send {
+        async move {
+            let mut hashes = Vec::new();
+            for height in heights {
+                hashes.push(self.block_hash(height).await?);
+            }
+            Ok(hashes)
+        }
+    }
+
+    /// The block with `hash`, whether or not it is on the active chain.
+    fn block(&self, hash: BlockHash) -> impl Future<Output = Result<Block, TrackerError>> + Send;
+
+    /// Transactions currently in the mempool.
+    fn mempool(&self) -> impl Future<Output = Result<Vec<Txid>, TrackerError>> + Send;
+
+    /// A mempool transaction, or a confirmed one if the source keeps a transaction index.
+    fn raw_tx(&self, txid: Txid) -> impl Future<Output = Result<Transaction, TrackerError>> + Send;
+
+    /// The output at `outpoint`, if it is confirmed and still unspent.
+    fn utxo(
+        &self,
+        outpoint: OutPoint,
+    ) -> impl Future<Output = Result<Option<Utxo>, TrackerError>> + Send;
+}
+
+/// A [`BlockSource`] whose calls fail with [`TrackerError::Shutdown`] once shutdown starts, rather
+/// than keep the exit waiting on a node that stopped answering.
+pub struct Interruptible<S> {
+    inner: S,
+    guard: WeakShutdownGuard,
+}
+
+impl<S: BlockSource> Interruptible<S> {
+    pub fn new(inner: S, guard: &ShutdownGuard) -> Self {
+        Interruptible {
+            inner,
+            guard: guard.clone_weak(),
+        }
+    }
+
+    async fn interrupt<T>(
+        &self,
+        call: impl Future<Output = Result<T, TrackerError>>,
+    ) -> Result<T, TrackerError> {
+        tokio::select! {
+            result = call => result,
+            _ = self.guard.cancelled() => Err(TrackerError::Shutdown),
+        }
+    }
+}
+
+impl<S: BlockSource> BlockSource for Interruptible<S> {
+    async fn tip_height(&self) -> Result<u64, TrackerError> {
+        self.interrupt(self.inner.tip_height()).await
+    }
+
+    async fn block_hash(&self, height: u64) -> Result<BlockHash, TrackerError> {
+        self.interrupt(self.inner.block_hash(height)).await
+    }
+
+    async fn block_hashes(
+        &self,
+        heights: RangeInclusive<u64>,
+    ) -> Result<Vec<BlockHash>, TrackerError> {
+        self.interrupt(self.inner.block_hashes(heights)).await
+    }
+
+    async fn block(&self, hash: BlockHash) -> Result<Block, TrackerError> {
+        self.interrupt(self.inner.block(hash)).await
+    }
+
+    async fn mempool(&self) -> Result<Vec<Txid>, TrackerError> {
+        self.interrupt(self.inner.mempool()).await
+    }
+
+    async fn raw_tx(&self, txid: Txid) -> Result<Transaction, TrackerError> {
+        self.interrupt(self.inner.raw_tx(txid)).await
+    }
+
+    async fn utxo(&self, outpoint: OutPoint) -> Result<Option<Utxo>, TrackerError> {
+        self.interrupt(self.inner.utxo(outpoint)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+
+    use tokio::sync::oneshot;
+    use tokio_graceful::Shutdown;
+
+    use super::*;
+
+    /// A node that has stopped answering.
+    struct Hung;
+
+    impl BlockSource for Hung {
+        async fn tip_height(&self) -> Result<u64, TrackerError> {
+            pending().await
+        }
+
+        async fn block_hash(&self, _: u64) -> Result<BlockHash, TrackerError> {
+            pending().await
+        }
+
+        async fn block(&self, _: BlockHash) -> Result<Block, TrackerError> {
+            pending().await
+        }
+
+        async fn mempool(&self) -> Result<Vec<Txid>, TrackerError> {
+            pending().await
+        }
+
+        async fn raw_tx(&self, _: Txid) -> Result<Transaction, TrackerError> {
+            pending().await
+        }
+
+        async fn utxo(&self, _: OutPoint) -> Result<Option<Utxo>, TrackerError> {
+            pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_hung_source_at_shutdown() {
+        let (signal_tx, signal_rx) = oneshot::channel::<()>();
+        let shutdown = Shutdown::new(async {
+            let _ = signal_rx.await;
+        });
+        let call = shutdown.spawn_task_fn(|guard| async move {
+            Interruptible::new(Hung, &guard).block_hashes(0..=10).await
+        });
+
+        signal_tx.send(()).unwrap();
+        assert!(matches!(call.await.unwrap(), Err(TrackerError::Shutdown)));
+        shutdown.shutdown().await;
+    }
+}
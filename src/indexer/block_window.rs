@@ -0,0 +1,59 @@
+use std::collections::VecDeque;
+
+use bitcoincore_rpc::bitcoin::BlockHash;
+
+use crate::types::Checkpoint;
+
+/// Number of recent blocks remembered, i.e. the deepest reorg that can be unwound precisely.
+pub const REORG_WINDOW: usize = 100;
+
+/// The most recent blocks processed by the indexer, oldest first.
+#[derive(Debug, Default)]
+pub struct BlockWindow {
+    blocks: VecDeque<(u64, BlockHash)>,
+}
+
+impl BlockWindow {
+    pub fn push(&mut self, height: u64, hash: BlockHash) {
+        if self.blocks.len() == REORG_WINDOW {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back((height, hash));
+    }
+
+    pub fn tip(&self) -> Option<(u64, BlockHash)> {
+        self.blocks.back().copied()
+    }
+
+    pub fn oldest(&self) -> Option<(u64, BlockHash)> {
+        self.blocks.front().copied()
+    }
+
+    /// Iterates from the newest block to the oldest.
+    pub fn iter_rev(&self) -> impl Iterator<Item = &(u64, BlockHash)> {
+        self.blocks.iter().rev()
+    }
+
+    /// Forgets every block above `height`.
+    pub fn truncate(&mut self, height: u64) {
+        while self.tip().is_some_and(|(h, _)| h > height) {
+            self.blocks.pop_back();
+        }
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            blocks: self.blocks.iter().copied().collect(),
+        }
+    }
+}
+
+impl From<Checkpoint> for BlockWindow {
+    fn from(checkpoint: Checkpoint) -> Self {
+        let mut window = BlockWindow::default();
+        for (height, hash) in checkpoint.blocks {
+            window.push(height, hash);
+        }
+        window
+    }
+}
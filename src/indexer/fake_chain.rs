@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use bitcoincore_rpc::bitcoin::{
+    Amount, Block, BlockHash, CompactTarget, Network, OutPoint, PublicKey, ScriptBuf, Sequence,
+    Transaction, TxIn, TxMerkleNode, TxOut, Txid, Witness,
+    absolute::LockTime,
+    block::{Header, Version},
+    blockdata::{constants::genesis_block, opcodes::all::OP_RETURN, script::Builder},
+    hashes::{Hash, sha256},
+    script::PushBytesBuf,
+    secp256k1::{Message, Secp256k1, SecretKey},
+    transaction,
+};
+
+use super::block_source::{BlockSource, Utxo};
+use crate::{
+    error::TrackerError,
+    fidelity::{cert_hash, fidelity_script_pubkey},
+    types::{FidelityBond, FidelityProof},
+};
+
+const BLOCK_SUBSIDY: Amount = Amount::from_sat(50 * 100_000_000);
+
+/// An in-memory chain to run the indexer against without a node.
+///
+/// Starts at the regtest genesis block. Tests mine blocks onto it, put transactions in its
+/// mempool and [`fork`](Self::fork) it to simulate reorgs. Clones share the same chain, so a test
+/// can keep extending it while the indexer reads from another clone.
+#[derive(Debug, Clone)]
+pub struct FakeChain {
+    state: Arc<Mutex<ChainState>>,
+}
+
+#[derive(Debug)]
+struct ChainState {
+    /// The active chain, from genesis to tip.
+    active: Vec<BlockHash>,
+    /// Every block ever mined, including those forked off the active chain.
+    blocks: HashMap<BlockHash, Block>,
+    mempool: Vec<Transaction>,
+    /// Bumped for every block, so blocks mined at the same height on different branches differ.
+    nonce: u32,
+}
+
+impl Default for FakeChain {
+    fn default() -> Self {
+        let genesis = genesis_block(Network::Regtest);
+        let hash = genesis.block_hash();
+        FakeChain {
+            state: Arc::new(Mutex::new(ChainState {
+                active: vec![hash],
+                blocks: HashMap::from([(hash, genesis)]),
+                mempool: Vec::new(),
+                nonce: 0,
+            })),
+        }
+    }
+}
+
+impl FakeChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, ChainState> {
+        self.state.lock().expect("fake chain lock poisoned")
+    }
+
+    pub fn height(&self) -> u64 {
+        self.state().active.len() as u64 - 1
+    }
+
+    /// Mines a block containing `txdata` after its coinbase onto the tip, dropping those
+    /// transactions from the mempool.
+    pub fn mine(&self, txdata: Vec<Transaction>) -> BlockHash {
+        let mut state = self.state();
+        let height = state.active.len() as u64;
+        let prev_blockhash = *state.active.last().expect("chain has a genesis block");
+        state.nonce += 1;
+
+        let coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new()
+                    .push_int(height as i64)
+                    .push_int(i64::from(state.nonce))
+                    .into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: BLOCK_SUBSIDY,
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let mut block = Block {
+            header: Header {
+                version: Version::ONE,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 1_700_000_000 + height as u32,
+                bits: CompactTarget::from_consensus(0x207f_ffff),
+                nonce: state.nonce,
+            },
+            txdata: [vec![coinbase], txdata].concat(),
+        };
+        if let Some(merkle_root) = block.compute_merkle_root() {
+            block.header.merkle_root = merkle_root;
+        }
+
+        let mined: Vec<Txid> = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
+        state
+            .mempool
+            .retain(|tx| !mined.contains(&tx.compute_txid()));
+        let hash = block.block_hash();
+        state.active.push(hash);
+        state.blocks.insert(hash, block);
+        hash
+    }
+
+    /// Mines `count` empty blocks.
+    pub fn mine_empty(&self, count: u64) {
+        for _ in 0..count {
+            self.mine(Vec::new());
+        }
+    }
+
+    /// Cuts the active chain back to `height`, so that the next blocks mined build a competing
+    /// branch. The blocks cut off stay retrievable by hash, as they do on a node.
+    pub fn fork(&self, height: u64) {
+        self.state().active.truncate(height as usize + 1);
+    }
+
+    pub fn add_to_mempool(&self, tx: Transaction) {
+        self.state().mempool.push(tx);
+    }
+
+    /// Drops a transaction from the mempool, as when it is evicted or replaced.
+    pub fn evict(&self, txid: Txid) {
+        self.state().mempool.retain(|tx| tx.compute_txid() != txid);
+    }
+}
+
+impl ChainState {
+    fn active_blocks(&self) -> impl Iterator<Item = &Block> {
+        self.active.iter().map(|hash| &self.blocks[hash])
+    }
+}
+
+/// A maker announcement, spending `funding`, that [`find_fidelity_bond`] picks up: an
+/// `OP_RETURN <address> <pubkey> <locktime>` output and a bond of `amount` locked until `lock_time`.
+///
+/// [`find_fidelity_bond`]: crate::fidelity::find_fidelity_bond
+pub fn maker_announcement(
+    funding: OutPoint,
+    address: &str,
+    pubkey: &PublicKey,
+    amount: Amount,
+    lock_time: LockTime,
+) -> Transaction {
+    let address =
+        PushBytesBuf::try_from(address.as_bytes().to_vec()).expect("address fits in a push");
+    let announcement = Builder::new()
+        .push_opcode(OP_RETURN)
+        .push_slice(address)
+        .push_key(pubkey)
+        .push_slice(lock_time.to_consensus_u32().to_le_bytes())
+        .into_script();
+    // Final, like any announcement a node would mine: the bond's locktime only lives in its script.
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: funding,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![
+            TxOut {
+                value: amount,
+                script_pubkey: fidelity_script_pubkey(lock_time, pubkey),
+            },
+            TxOut {
+                value: Amount::ZERO,
+                script_pubkey: announcement,
+            },
+        ],
+    }
+}
+
+/// The certificate a maker holding `key` makes to register `url` with `bond`.
+pub fn maker_proof(bond: FidelityBond, url: &str, key: &SecretKey) -> FidelityProof {
+    let cert_hash = cert_hash(&bond, url);
+    let digest = sha256::Hash::hash(cert_hash.as_byte_array());
+    let cert_sig = Secp256k1::new().sign_ecdsa(&Message::from_digest(digest.to_byte_array()), key);
+    FidelityProof {
+        bond,
+        cert_hash,
+        cert_sig,
+    }
+}
+
+fn not_found(what: String) -> TrackerError {
+    TrackerError::RPCError(bitcoincore_rpc::Error::ReturnedError(what))
+}
+
+impl BlockSource for FakeChain {
+    async fn tip_height(&self) -> Result<u64, TrackerError> {
+        Ok(self.height())
+    }
+
+    async fn block_hash(&self, height: u64) -> Result<BlockHash, TrackerError> {
+        let state = self.state();
+        usize::try_from(height)
+            .ok()
+            .and_then(|height| state.active.get(height).copied())
+            .ok_or_else(|| not_found(format!("block height {height} out of range")))
+    }
+
+    async fn block(&self, hash: BlockHash) -> Result<Block, TrackerError> {
+        let state = self.state();
+        state
+            .blocks
+            .get(&hash)
+            .cloned()
+            .ok_or_else(|| not_found(format!("block {hash} not found")))
+    }
+
+    async fn mempool(&self) -> Result<Vec<Txid>, TrackerError> {
+        Ok(self
+            .state()
+            .mempool
+            .iter()
+            .map(|tx| tx.compute_txid())
+            .collect())
+    }
+
+    async fn raw_tx(&self, txid: Txid) -> Result<Transaction, TrackerError> {
+        let state = self.state();
+        let found = state
+            .mempool
+            .iter()
+            .chain(state.active_blocks().flat_map(|block| &block.txdata))
+            .find(|tx| tx.compute_txid() == txid)
+            .cloned();
+        found.ok_or_else(|| not_found(format!("transaction {txid} not found")))
+    }
+
+    async fn utxo(&self, outpoint: OutPoint) -> Result<Option<Utxo>, TrackerError> {
+        let state = self.state();
+        let tip_height = state.active.len() as u32 - 1;
+        let mut found = None;
+        for (height, block) in state.active_blocks().enumerate() {
+            for tx in &block.txdata {
+                if tx.input.iter().any(|txin| txin.previous_output == outpoint) {
+                    return Ok(None);
+                }
+                if tx.compute_txid() == outpoint.txid
+                    && let Some(txout) = tx.output.get(outpoint.vout as usize)
+                {
+                    found = Some(Utxo {
+                        value: txout.value,
+                        script_pubkey: txout.script_pubkey.clone(),
+                        confirmations: tip_height - height as u32 + 1,
+                    });
+                }
+            }
+        }
+        Ok(found)
+    }
+}
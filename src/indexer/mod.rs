@@ -1,3 +1,9 @@
+mod block_source;
+mod block_window;
+#[cfg(test)]
+pub mod fake_chain;
 mod tracker_indexer;
-pub use tracker_indexer::run;
+pub use block_source::{BlockSource, Interruptible};
+pub use tracker_indexer::{IndexerConfig, run};
 mod rpc;
+pub use rpc::BitcoinRpc;
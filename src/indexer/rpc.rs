@@ -1,50 +1,275 @@
+use std::{
+    fs,
+    ops::RangeInclusive,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
 use bitcoincore_rpc::{
     Auth, Client, RpcApi,
-    bitcoin::{Block, BlockHash, Transaction, Txid},
-    json::GetBlockchainInfoResult,
+    bitcoin::{Block, BlockHash, Network, OutPoint, ScriptBuf, Transaction, Txid},
+    json::{GetBlockchainInfoResult, GetTxOutResult},
+    jsonrpc::{self, simple_http::SimpleHttpTransport},
 };
+use tokio::task::spawn_blocking;
+use tracing::warn;
+
+use super::block_source::{BlockSource, Utxo};
+use crate::{RPCConfig, error::TrackerError};
 
-use crate::error::TrackerError;
+/// Longest a single RPC request may take before it counts as failed.
+const RPC_TIMEOUT: Duration = Duration::from_secs(60);
+/// Attempts at a call that keeps failing transiently, e.g. while bitcoind restarts.
+const RPC_ATTEMPTS: u32 = 4;
+/// Pause before the first retry, doubled for every further one.
+const RPC_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// bitcoind's "in warmup" error code, returned while it is still loading.
+const RPC_IN_WARMUP: i32 = -28;
 
+/// A bitcoind RPC client that is safe to use from async code. Calls run on tokio's blocking pool,
+/// their requests time out at the transport, and they are retried when the node is briefly
+/// unreachable. With cookie authentication, the cookie is read again whenever a call fails to get
+/// through, so the tracker keeps working across bitcoind restarts.
+#[derive(Clone)]
 pub struct BitcoinRpc {
-    client: Client,
+    inner: Arc<BlockingRpc>,
+}
+
+/// The blocking client behind [`BitcoinRpc`].
+struct BlockingRpc {
+    config: RPCConfig,
+    client: RwLock<Client>,
+    /// The cookie the current client was built with, if authenticating by cookie.
+    cookie: RwLock<Option<String>>,
 }
 
 impl BitcoinRpc {
-    pub fn new(url: String, username: String, password: String) -> Result<Self, TrackerError> {
-        let auth = Auth::UserPass(username, password);
-        let client = Client::new(&url, auth)?;
-        Ok(Self { client })
+    pub fn new(config: RPCConfig) -> Result<Self, TrackerError> {
+        let cookie = read_cookie(&config.auth)?;
+        let client = connect(&config)?;
+        Ok(Self {
+            inner: Arc::new(BlockingRpc {
+                config,
+                client: RwLock::new(client),
+                cookie: RwLock::new(cookie),
+            }),
+        })
+    }
+
+    /// Runs `f` against the client off the async runtime, retrying with a growing delay while it
+    /// fails transiently.
+    async fn call<T, F>(&self, f: F) -> Result<T, TrackerError>
+    where
+        T: Send + 'static,
+        F: Fn(&Client) -> Result<T, bitcoincore_rpc::Error> + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let mut delay = RPC_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            let inner = self.inner.clone();
+            let call = f.clone();
+            let error = match spawn_blocking(move || inner.call(&*call)).await {
+                Ok(Err(e)) if is_transient(&e) => e,
+                Ok(result) => return result,
+                Err(e) => return Err(TrackerError::General(format!("RPC call panicked: {e}"))),
+            };
+            if attempt == RPC_ATTEMPTS {
+                return Err(error);
+            }
+            warn!("RPC call failed (attempt {attempt}/{RPC_ATTEMPTS}), retrying: {error}");
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    pub async fn get_raw_mempool(&self) -> Result<Vec<Txid>, TrackerError> {
+        self.call(|c| c.get_raw_mempool()).await
+    }
+
+    pub async fn get_raw_tx(&self, txid: &Txid) -> Result<Transaction, TrackerError> {
+        let txid = *txid;
+        self.call(move |c| c.get_raw_transaction(&txid, None)).await
+    }
+
+    pub async fn get_blockchain_info(&self) -> Result<GetBlockchainInfoResult, TrackerError> {
+        self.call(|c| c.get_blockchain_info()).await
+    }
+
+    /// Checks that the node is on `chain`, and returns whether it has caught up with it. A fresh
+    /// regtest node stays in initial block download until a block is mined, so there only the
+    /// headers count.
+    pub async fn check_node(&self, chain: Network) -> Result<bool, TrackerError> {
+        let info = self.get_blockchain_info().await?;
+        if info.chain != chain {
+            return Err(TrackerError::General(format!(
+                "bitcoind is on {} but the tracker is configured for {}",
+                info.chain, chain
+            )));
+        }
+        let downloading = info.initial_block_download && chain != Network::Regtest;
+        Ok(!downloading && info.blocks == info.headers)
+    }
+
+    pub async fn get_block_hash(&self, height: u64) -> Result<BlockHash, TrackerError> {
+        self.call(move |c| c.get_block_hash(height)).await
+    }
+
+    /// Hashes of the blocks at `heights`, fetched in a single JSON-RPC batch.
+    pub async fn get_block_hashes(
+        &self,
+        heights: RangeInclusive<u64>,
+    ) -> Result<Vec<BlockHash>, TrackerError> {
+        self.call(move |c| get_block_hashes(c, heights.clone()))
+            .await
+    }
+
+    pub async fn get_block(&self, hash: BlockHash) -> Result<Block, TrackerError> {
+        self.call(move |c| c.get_block(&hash)).await
     }
 
-    pub fn get_raw_mempool(&self) -> Result<Vec<Txid>, TrackerError> {
-        let raw_mempool = self.client.get_raw_mempool()?;
-        Ok(raw_mempool)
+    /// Looks up a confirmed, unspent output.
+    pub async fn get_tx_out(
+        &self,
+        outpoint: &OutPoint,
+    ) -> Result<Option<GetTxOutResult>, TrackerError> {
+        let outpoint = *outpoint;
+        self.call(move |c| c.get_tx_out(&outpoint.txid, outpoint.vout, Some(false)))
+            .await
+    }
+}
+
+impl BlockSource for BitcoinRpc {
+    async fn tip_height(&self) -> Result<u64, TrackerError> {
+        Ok(self.get_blockchain_info().await?.blocks)
     }
 
-    pub fn get_raw_tx(&self, txid: &Txid) -> Result<Transaction, TrackerError> {
-        let tx = self.client.get_raw_transaction(txid, None)?;
-        Ok(tx)
+    async fn block_hash(&self, height: u64) -> Result<BlockHash, TrackerError> {
+        self.get_block_hash(height).await
     }
 
-    pub fn get_blockchain_info(&self) -> Result<GetBlockchainInfoResult, TrackerError> {
-        let blockchain_info = self.client.get_blockchain_info()?;
-        Ok(blockchain_info)
+    async fn block_hashes(
+        &self,
+        heights: RangeInclusive<u64>,
+    ) -> Result<Vec<BlockHash>, TrackerError> {
+        self.get_block_hashes(heights).await
     }
 
-    pub fn get_block_hash(&self, height: u64) -> Result<BlockHash, TrackerError> {
-        let block_hash = self.client.get_block_hash(height)?;
-        Ok(block_hash)
+    async fn block(&self, hash: BlockHash) -> Result<Block, TrackerError> {
+        self.get_block(hash).await
     }
 
-    pub fn get_block(&self, hash: BlockHash) -> Result<Block, TrackerError> {
-        let block = self.client.get_block(&hash)?;
-        Ok(block)
+    async fn mempool(&self) -> Result<Vec<Txid>, TrackerError> {
+        self.get_raw_mempool().await
+    }
+
+    async fn raw_tx(&self, txid: Txid) -> Result<Transaction, TrackerError> {
+        self.get_raw_tx(&txid).await
+    }
+
+    async fn utxo(&self, outpoint: OutPoint) -> Result<Option<Utxo>, TrackerError> {
+        let Some(tx_out) = self.get_tx_out(&outpoint).await? else {
+            return Ok(None);
+        };
+        Ok(Some(Utxo {
+            value: tx_out.value,
+            script_pubkey: ScriptBuf::from_bytes(tx_out.script_pub_key.hex),
+            confirmations: tx_out.confirmations,
+        }))
+    }
+}
+
+impl BlockingRpc {
+    /// Runs `f` against the client, retrying once with a fresh client if the call failed at the
+    /// transport level and the cookie has changed since, as it does when bitcoind restarts.
+    fn call<T>(
+        &self,
+        f: impl Fn(&Client) -> Result<T, bitcoincore_rpc::Error>,
+    ) -> Result<T, TrackerError> {
+        let result = f(&self.client.read().expect("rpc client lock poisoned"));
+        match result {
+            Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(_)))
+                if self.refresh_cookie()? =>
+            {
+                Ok(f(&self.client.read().expect("rpc client lock poisoned"))?)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Rebuilds the client if the cookie file now holds a different cookie. Returns whether it did.
+    fn refresh_cookie(&self) -> Result<bool, TrackerError> {
+        // bitcoind may be down, with no cookie file; the original error is the one worth reporting.
+        let Ok(cookie @ Some(_)) = read_cookie(&self.config.auth) else {
+            return Ok(false);
+        };
+        let mut current = self.cookie.write().expect("rpc cookie lock poisoned");
+        if *current == cookie {
+            return Ok(false);
+        }
+        let client = connect(&self.config)?;
+        *self.client.write().expect("rpc client lock poisoned") = client;
+        *current = cookie;
+        Ok(true)
+    }
+}
+
+/// A client for `config` whose requests give up after `RPC_TIMEOUT`. A timeout around the blocking
+/// call couldn't cancel it, so it has to be the transport that stops waiting.
+fn connect(config: &RPCConfig) -> Result<Client, TrackerError> {
+    let (user, pass) = config.auth.clone().get_user_pass()?;
+    let mut transport = SimpleHttpTransport::builder()
+        .url(&config.url)
+        .map_err(|e| bitcoincore_rpc::Error::JsonRpc(e.into()))?
+        .timeout(RPC_TIMEOUT);
+    if let Some(user) = user {
+        transport = transport.auth(user, pass);
+    }
+    Ok(Client::from_jsonrpc(jsonrpc::Client::with_transport(
+        transport.build(),
+    )))
+}
+
+fn get_block_hashes(
+    client: &Client,
+    heights: RangeInclusive<u64>,
+) -> Result<Vec<BlockHash>, bitcoincore_rpc::Error> {
+    let rpc = client.get_jsonrpc_client();
+    let params: Vec<_> = heights.map(|height| jsonrpc::arg([height])).collect();
+    if params.is_empty() {
+        return Ok(Vec::new());
+    }
+    let requests: Vec<_> = params
+        .iter()
+        .map(|params| rpc.build_request("getblockhash", Some(params)))
+        .collect();
+    rpc.send_batch(&requests)?
+        .into_iter()
+        .map(|response| {
+            let response = response.ok_or_else(|| {
+                bitcoincore_rpc::Error::ReturnedError("missing getblockhash response".to_string())
+            })?;
+            Ok(response.result()?)
+        })
+        .collect()
+}
+
+/// Whether a failed call is worth retrying: the node could not be reached, or is still starting.
+fn is_transient(error: &TrackerError) -> bool {
+    match error {
+        TrackerError::RPCError(bitcoincore_rpc::Error::JsonRpc(e)) => match e {
+            jsonrpc::Error::Transport(_) => true,
+            jsonrpc::Error::Rpc(e) => e.code == RPC_IN_WARMUP,
+            _ => false,
+        },
+        _ => false,
     }
 }
 
-impl From<Client> for BitcoinRpc {
-    fn from(value: Client) -> Self {
-        BitcoinRpc { client: value }
+fn read_cookie(auth: &Auth) -> Result<Option<String>, TrackerError> {
+    match auth {
+        Auth::CookieFile(path) => Ok(Some(fs::read_to_string(path)?)),
+        _ => Ok(None),
     }
 }
@@ -1,103 +1,574 @@
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
 
-use tokio::{sync::mpsc::Sender, time::Instant};
+use tokio::{sync::mpsc, time::Instant};
 
-use bitcoincore_rpc::bitcoin::absolute::{Height, LockTime};
-use tracing::info;
+use bitcoincore_rpc::bitcoin::{Amount, Block, Txid};
+use tokio_graceful::ShutdownGuard;
+use tracing::{info, warn};
 
-use super::rpc::BitcoinRpc;
+use super::{
+    block_source::{BlockSource, Interruptible},
+    block_window::{BlockWindow, REORG_WINDOW},
+};
 use crate::{
+    db::DbHandle,
+    error::TrackerError,
+    fidelity::find_fidelity_bond,
     handle_result, status,
-    types::{DbRequest, ServerInfo},
+    types::{Checkpoint, DbRequest, FidelityBond, ProbeHistory, ServerInfo},
+    utils::shutdown_requested,
 };
 
-pub async fn run(db_tx: Sender<DbRequest>, status_tx: status::Sender, client: BitcoinRpc) {
+/// How many blocks may be processed before the progress is checkpointed mid-scan.
+const CHECKPOINT_INTERVAL: u64 = 100;
+/// How many block hashes are requested in one JSON-RPC batch.
+const HASH_BATCH_SIZE: u64 = 100;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IndexerConfig {
+    /// Height to start from when there is no checkpoint, or when the checkpoint is older.
+    pub start_height: u64,
+    /// Also pick up announcements that are still in the mempool.
+    pub scan_mempool: bool,
+}
+
+pub async fn run<S: BlockSource>(
+    db_tx: DbHandle,
+    status_tx: status::Sender,
+    client: S,
+    config: IndexerConfig,
+    guard: ShutdownGuard,
+) {
     info!("Indexer started");
-    let mut last_tip = 0;
+    // A hung node must not keep the indexer, and with it the DB manager, from stopping.
+    let client = Interruptible::new(client, &guard);
+    let IndexerConfig {
+        start_height,
+        scan_mempool,
+    } = config;
+    let mut window = match query_checkpoint(&db_tx).await {
+        Some(checkpoint) => {
+            info!("Resuming from checkpoint {:?}", checkpoint.blocks.last());
+            BlockWindow::from(checkpoint)
+        }
+        None => BlockWindow::default(),
+    };
+    // What was seen in the mempool before a restart is unknown, so start over from the current
+    // mempool rather than risk keeping evicted announcements around forever.
+    if db_tx.send(DbRequest::ClearUnconfirmed).await.is_err() {
+        warn!("DB manager unavailable, cannot clear unconfirmed makers");
+    }
+    let mut mempool = HashMap::new();
+    let mut next_height = window
+        .tip()
+        .map_or(start_height, |(height, _)| (height + 1).max(start_height));
     loop {
-        tokio::time::sleep(Duration::from_secs(10)).await;
-        let blockchain_info = handle_result!(status_tx, client.get_blockchain_info());
-        let tip_height = blockchain_info.blocks;
-        for height in last_tip..tip_height {
-            let block_hash = handle_result!(status_tx, client.get_block_hash(height));
-            let block = handle_result!(status_tx, client.get_block(block_hash));
-            for tx in block.txdata {
-                if tx.lock_time == LockTime::Blocks(Height::ZERO) {
-                    continue;
-                }
-                if tx.output.len() != 2 {
-                    continue;
-                }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(10)) => {}
+            _ = guard.cancelled() => break,
+        }
+        let tip_height = handle_result!(status_tx, client.tip_height().await);
 
-                let onion_address = tx.output.iter().find_map(|txout| {
-                    extract_onion_address_from_script(txout.script_pubkey.as_bytes())
-                });
+        // A reorg to a chain that is not longer than ours never yields a block at `next_height`,
+        // so check that our tip is still part of the active chain.
+        if let Some((height, hash)) = window.tip() {
+            let reorged = height > tip_height
+                || handle_result!(status_tx, client.block_hash(height).await) != hash;
+            if reorged {
+                warn!("Indexed tip {hash} at height {height} is no longer on the active chain");
+                next_height = handle_result!(
+                    status_tx,
+                    rollback(&client, &db_tx, &mut window, tip_height).await
+                );
+            }
+        }
 
-                if let Some(onion_address) = onion_address {
-                    let server_info = ServerInfo {
-                        onion_address: onion_address.clone(),
-                        cooldown: Instant::now(),
-                        stale: false,
-                    };
-                    info!("New address found: {:?}", onion_address);
-                    let db_request = DbRequest::Add(onion_address, server_info);
+        // Block hashes are fetched a batch at a time, ahead of the blocks themselves.
+        let mut hashes = VecDeque::new();
+        while next_height <= tip_height && !shutdown_requested(&guard) {
+            let height = next_height;
+            if hashes.is_empty() {
+                let last = tip_height.min(height + HASH_BATCH_SIZE - 1);
+                let batch = client.block_hashes(height..=last).await;
+                hashes = handle_result!(status_tx, batch).into();
+            }
+            let Some(&block_hash) = hashes.front() else {
+                break;
+            };
+            let block = handle_result!(status_tx, client.block(block_hash).await);
 
-                    handle_result!(status_tx, db_tx.send(db_request).await);
-                }
+            if let Some((prev_height, prev_hash)) = window.tip()
+                && prev_height + 1 == height
+                && block.header.prev_blockhash != prev_hash
+            {
+                warn!("Block {block_hash} at height {height} does not extend {prev_hash}");
+                hashes.clear();
+                next_height = handle_result!(
+                    status_tx,
+                    rollback(&client, &db_tx, &mut window, tip_height).await
+                );
+                continue;
+            }
+
+            // Only move past the block once every announcement in it has been handed to the DB
+            // manager. That is not proof they were stored: requests still queued, or applied but
+            // not yet flushed, when the DB manager restarts are lost, and a later checkpoint then
+            // covers their block regardless.
+            handle_result!(status_tx, index_block(&db_tx, height, block).await);
+            hashes.pop_front();
+            window.push(height, block_hash);
+            next_height += 1;
+            if height == tip_height || next_height % CHECKPOINT_INTERVAL == 0 {
+                handle_result!(
+                    status_tx,
+                    db_tx
+                        .send(DbRequest::SetCheckpoint(window.checkpoint()))
+                        .await
+                );
             }
         }
-        last_tip = tip_height;
+
+        // Runs after the block scan so that an announcement which just confirmed is already
+        // promoted by the time it disappears from the mempool.
+        if scan_mempool && !shutdown_requested(&guard) {
+            handle_result!(
+                status_tx,
+                index_mempool(&client, &db_tx, &mut mempool).await
+            );
+        }
     }
+
+    // Blocks processed since the last periodic checkpoint would otherwise be re-scanned.
+    let _ = db_tx
+        .send(DbRequest::SetCheckpoint(window.checkpoint()))
+        .await;
+    info!("Indexer stopped");
 }
 
-fn extract_onion_address_from_script(script: &[u8]) -> Option<String> {
-    if script.is_empty() || script[0] != 0x6a {
-        return None;
+/// Adds the makers announced in `block`, mined at `height`, to the registry.
+async fn index_block(db_tx: &DbHandle, height: u64, block: Block) -> Result<(), TrackerError> {
+    for tx in block.txdata {
+        if let Some((onion_address, bond)) = find_fidelity_bond(&tx) {
+            let server_info = ServerInfo {
+                onion_address: onion_address.clone(),
+                cooldown: Instant::now(),
+                stale: false,
+                live_since: Instant::now(),
+                last_ping: None,
+                found_height: Some(height),
+                unconfirmed: false,
+                bond: FidelityBond {
+                    conf_height: Some(height as u32),
+                    ..bond
+                },
+                pending_bond: None,
+                bond_value: Amount::ZERO,
+                history: ProbeHistory::default(),
+            };
+            info!(
+                "New address found: {:?}, bond: {}",
+                onion_address, bond.outpoint
+            );
+            db_tx
+                .send(DbRequest::Add(onion_address, server_info))
+                .await?;
+        }
     }
-    if script.len() < 2 {
-        return None;
+    Ok(())
+}
+
+/// Syncs the unconfirmed entries in the registry with the current mempool.
+///
+/// `seen` maps every mempool transaction already inspected to the address it announces, if any,
+/// so each transaction is only fetched once.
+async fn index_mempool(
+    client: &impl BlockSource,
+    db_tx: &DbHandle,
+    seen: &mut HashMap<Txid, Option<String>>,
+) -> Result<(), TrackerError> {
+    let mempool: HashSet<Txid> = client.mempool().await?.into_iter().collect();
+
+    // Evictions go first: a replacement announcing the same address must not be dropped along
+    // with the transaction it replaced.
+    let gone: Vec<Txid> = seen
+        .keys()
+        .filter(|txid| !mempool.contains(*txid))
+        .copied()
+        .collect();
+    for txid in gone {
+        if let Some(Some(onion_address)) = seen.remove(&txid) {
+            info!("Announcement {txid} left the mempool: {onion_address:?}");
+            db_tx
+                .send(DbRequest::DropUnconfirmed(onion_address))
+                .await?;
+        }
     }
-    let (data_start, data_len) = match script[1] {
-        n @ 0x01..=0x4b => (2, n as usize),
-        0x4c => {
-            if script.len() < 3 {
-                return None;
-            }
-            (3, script[2] as usize)
+
+    for txid in mempool {
+        if seen.contains_key(&txid) {
+            continue;
         }
-        0x4d => {
-            if script.len() < 4 {
-                return None;
+        // The transaction may have been mined or evicted since the mempool was listed.
+        let tx = match client.raw_tx(txid).await {
+            Ok(tx) => tx,
+            Err(e) => {
+                warn!("Failed to fetch mempool transaction {txid}: {e:?}");
+                continue;
             }
-            let len = u16::from_le_bytes([script[2], script[3]]) as usize;
-            (4, len)
+        };
+        let announcement = find_fidelity_bond(&tx);
+        if let Some((onion_address, bond)) = &announcement {
+            let server_info = ServerInfo {
+                onion_address: onion_address.clone(),
+                cooldown: Instant::now(),
+                stale: false,
+                live_since: Instant::now(),
+                last_ping: None,
+                found_height: None,
+                unconfirmed: true,
+                bond: bond.clone(),
+                pending_bond: None,
+                bond_value: Amount::ZERO,
+                history: ProbeHistory::default(),
+            };
+            info!(
+                "Unconfirmed address found: {:?}, bond: {}",
+                onion_address, bond.outpoint
+            );
+            db_tx
+                .send(DbRequest::Add(onion_address.clone(), server_info))
+                .await?;
         }
-        _ => {
-            return None;
+        seen.insert(txid, announcement.map(|(onion_address, _)| onion_address));
+    }
+    Ok(())
+}
+
+/// Unwinds the registry and `window` to the last remembered block that is still on the active
+/// chain, returning the height to resume indexing from.
+async fn rollback(
+    client: &impl BlockSource,
+    db_tx: &DbHandle,
+    window: &mut BlockWindow,
+    tip_height: u64,
+) -> Result<u64, TrackerError> {
+    let mut fork_height = None;
+    for &(height, hash) in window.iter_rev() {
+        if height <= tip_height && client.block_hash(height).await? == hash {
+            fork_height = Some(height);
+            break;
+        }
+    }
+    let fork_height = match fork_height {
+        Some(height) => height,
+        None => {
+            let oldest = window.oldest().map_or(0, |(height, _)| height);
+            warn!("Reorg deeper than {REORG_WINDOW} blocks, re-indexing from height {oldest}");
+            oldest.saturating_sub(1)
         }
     };
-    if script.len() < data_start + data_len {
+    info!("Rolling back to fork point at height {fork_height}");
+
+    window.truncate(fork_height);
+    db_tx.send(DbRequest::Rollback(fork_height)).await?;
+    db_tx
+        .send(DbRequest::SetCheckpoint(window.checkpoint()))
+        .await?;
+    Ok(fork_height + 1)
+}
+
+async fn query_checkpoint(db_tx: &DbHandle) -> Option<Checkpoint> {
+    let (resp_tx, mut resp_rx) = mpsc::channel(1);
+    if db_tx
+        .send(DbRequest::QueryCheckpoint(resp_tx))
+        .await
+        .is_err()
+    {
+        warn!("DB manager unavailable, cannot restore indexer checkpoint");
         return None;
     }
+    resp_rx.recv().await.flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use bitcoincore_rpc::bitcoin::{
+        BlockHash, OutPoint, PublicKey, Transaction,
+        absolute::LockTime,
+        hashes::Hash,
+        secp256k1::{Secp256k1, SecretKey},
+    };
+    use tokio::time::sleep;
+    use tokio_graceful::Shutdown;
+
+    use super::*;
+    use crate::indexer::fake_chain::{FakeChain, maker_announcement};
+
+    /// The indexer's poll interval. Time is paused in these tests, so sleeping this long lets the
+    /// indexer run exactly one more round.
+    const ROUND: Duration = Duration::from_secs(10);
+
+    /// Stands in for the DB manager: applies the indexer's requests to an in-memory registry
+    /// with the same rules, and records the rollbacks and checkpoints.
+    #[derive(Default)]
+    struct FakeDb {
+        makers: HashMap<String, ServerInfo>,
+        rollbacks: Vec<u64>,
+        checkpoints: Vec<Checkpoint>,
+    }
 
-    let data = &script[data_start..data_start + data_len];
-    let decoded = String::from_utf8(data.to_vec()).ok()?;
-    if is_valid_onion_address(&decoded) {
-        Some(decoded)
-    } else {
-        None
+    impl FakeDb {
+        fn apply(&mut self, request: DbRequest) {
+            match request {
+                // A mempool sighting must not demote an entry that already confirmed.
+                DbRequest::Add(address, info)
+                    if self.makers.get(&address).is_none_or(|m| m.unconfirmed) =>
+                {
+                    self.makers.insert(address, info);
+                }
+                DbRequest::Rollback(height) => {
+                    self.rollbacks.push(height);
+                    self.makers
+                        .retain(|_, info| info.found_height.is_none_or(|h| h <= height));
+                }
+                DbRequest::DropUnconfirmed(address)
+                    if self.makers.get(&address).is_some_and(|m| m.unconfirmed) =>
+                {
+                    self.makers.remove(&address);
+                }
+                DbRequest::ClearUnconfirmed => self.makers.retain(|_, info| !info.unconfirmed),
+                DbRequest::SetCheckpoint(checkpoint) => self.checkpoints.push(checkpoint),
+                DbRequest::QueryCheckpoint(resp_tx) => {
+                    let _ = resp_tx.try_send(self.checkpoints.last().cloned());
+                }
+                _ => {}
+            }
+        }
+
+        /// Registered makers, with the height they were found at or `None` if unconfirmed.
+        fn makers(&self) -> Vec<(String, Option<u64>)> {
+            let mut makers: Vec<_> = self
+                .makers
+                .iter()
+                .map(|(address, info)| (address.clone(), info.found_height))
+                .collect();
+            makers.sort();
+            makers
+        }
+
+        fn checkpoint_tip(&self) -> Option<(u64, BlockHash)> {
+            self.checkpoints.last()?.blocks.last().copied()
+        }
     }
-}
 
-fn is_valid_onion_address(s: &str) -> bool {
-    let parts: Vec<&str> = s.split(':').collect();
-    if parts.len() != 2 {
-        return false;
+    /// Runs the indexer against `chain` from the genesis block, returning the registry it fills
+    /// and the shutdown that stops it. Returns halfway between two of the indexer's rounds, so
+    /// that the test's own [`ROUND`]s never coincide with them.
+    async fn start(chain: &FakeChain, scan_mempool: bool) -> (Arc<Mutex<FakeDb>>, Shutdown) {
+        let (db_tx, mut db_rx) = mpsc::channel(64);
+        let db = Arc::new(Mutex::new(FakeDb::default()));
+        let registry = db.clone();
+        tokio::spawn(async move {
+            while let Some(request) = db_rx.recv().await {
+                registry.lock().unwrap().apply(request);
+            }
+        });
+
+        let (status_tx, _) = mpsc::channel(16);
+        let chain = chain.clone();
+        let config = IndexerConfig {
+            start_height: 0,
+            scan_mempool,
+        };
+        let shutdown = Shutdown::new(std::future::pending::<()>());
+        shutdown.spawn_task_fn(move |guard| {
+            run(
+                DbHandle::new(db_tx),
+                status::Sender::Mempool(status_tx),
+                chain,
+                config,
+                guard,
+            )
+        });
+        sleep(ROUND / 2).await;
+        (db, shutdown)
+    }
+
+    /// Maker `n`'s address and an announcement of it.
+    fn announcement(n: u8) -> (String, Transaction) {
+        let address = format!("maker{n}.onion:6102");
+        let key = SecretKey::from_slice(&[n; 32]).unwrap();
+        let pubkey = PublicKey::new(key.public_key(&Secp256k1::new()));
+        let funding = OutPoint::new(Txid::from_byte_array([n; 32]), 0);
+        let lock_time = LockTime::from_height(500_000).unwrap();
+        let tx = maker_announcement(funding, &address, &pubkey, Amount::ONE_BTC, lock_time);
+        (address, tx)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn indexes_new_announcement() {
+        let chain = FakeChain::new();
+        chain.mine_empty(2);
+        let (db, _shutdown) = start(&chain, false).await;
+        sleep(ROUND).await;
+        assert!(db.lock().unwrap().makers().is_empty());
+
+        let (address, tx) = announcement(1);
+        let bond = find_fidelity_bond(&tx).unwrap().1;
+        let hash = chain.mine(vec![tx]);
+        sleep(ROUND).await;
+
+        let db = db.lock().unwrap();
+        assert_eq!(db.makers(), vec![(address.clone(), Some(3))]);
+        let info = &db.makers[&address];
+        assert!(!info.unconfirmed);
+        assert_eq!(info.bond.outpoint, bond.outpoint);
+        assert_eq!(info.bond.conf_height, Some(3));
+        assert_eq!(db.checkpoint_tip(), Some((3, hash)));
+        assert!(db.rollbacks.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follows_announcement_across_fork() {
+        let chain = FakeChain::new();
+        chain.mine_empty(2);
+        let (address, tx) = announcement(1);
+        chain.mine(vec![tx.clone()]);
+        chain.mine_empty(1);
+        let (db, _shutdown) = start(&chain, false).await;
+        sleep(ROUND).await;
+        assert_eq!(
+            db.lock().unwrap().makers(),
+            vec![(address.clone(), Some(3))]
+        );
+
+        // The competing branch mines the same announcement one block later.
+        chain.fork(2);
+        chain.mine_empty(1);
+        chain.mine(vec![tx]);
+        let tip = chain.mine(Vec::new());
+        sleep(ROUND).await;
+
+        let db = db.lock().unwrap();
+        assert_eq!(db.rollbacks, vec![2]);
+        assert_eq!(db.makers(), vec![(address, Some(4))]);
+        assert_eq!(db.checkpoint_tip(), Some((5, tip)));
     }
-    let domain = parts[0];
-    let port = parts[1];
-    if !domain.ends_with(".onion") {
-        return false;
+
+    #[tokio::test(start_paused = true)]
+    async fn promotes_and_evicts_mempool_announcements() {
+        let chain = FakeChain::new();
+        chain.mine_empty(2);
+        let (db, _shutdown) = start(&chain, true).await;
+
+        let (confirmed, tx) = announcement(1);
+        chain.add_to_mempool(tx.clone());
+        sleep(ROUND).await;
+        assert_eq!(db.lock().unwrap().makers(), vec![(confirmed.clone(), None)]);
+
+        chain.mine(vec![tx]);
+        let (evicted, tx) = announcement(2);
+        let txid = tx.compute_txid();
+        chain.add_to_mempool(tx);
+        sleep(ROUND).await;
+        assert_eq!(
+            db.lock().unwrap().makers(),
+            vec![(confirmed.clone(), Some(3)), (evicted, None)]
+        );
+
+        chain.evict(txid);
+        sleep(ROUND).await;
+        assert_eq!(db.lock().unwrap().makers(), vec![(confirmed, Some(3))]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rolls_back_same_height_reorg() {
+        let chain = FakeChain::new();
+        chain.mine_empty(2);
+        let (orphaned, tx) = announcement(1);
+        chain.mine(vec![tx]);
+        let (db, _shutdown) = start(&chain, false).await;
+        sleep(ROUND).await;
+        assert_eq!(db.lock().unwrap().makers(), vec![(orphaned, Some(3))]);
+
+        // The competing tip is at the same height, so no new block ever shows up at the next one.
+        chain.fork(2);
+        let (replacement, tx) = announcement(2);
+        let tip = chain.mine(vec![tx]);
+        sleep(ROUND).await;
+
+        let db = db.lock().unwrap();
+        assert_eq!(db.rollbacks, vec![2]);
+        assert_eq!(db.makers(), vec![(replacement, Some(3))]);
+        assert_eq!(db.checkpoint_tip(), Some((3, tip)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rolls_back_to_longer_branch() {
+        let chain = FakeChain::new();
+        chain.mine_empty(2);
+        let (kept, tx) = announcement(1);
+        chain.mine(vec![tx]);
+        let (orphaned, tx) = announcement(2);
+        chain.mine(vec![tx]);
+        chain.mine_empty(1);
+        let (db, _shutdown) = start(&chain, false).await;
+        sleep(ROUND).await;
+        assert_eq!(
+            db.lock().unwrap().makers(),
+            vec![(kept.clone(), Some(3)), (orphaned, Some(4))]
+        );
+
+        chain.fork(3);
+        chain.mine_empty(1);
+        let (replacement, tx) = announcement(3);
+        chain.mine(vec![tx]);
+        let tip = chain.mine(Vec::new());
+        sleep(ROUND).await;
+
+        let db = db.lock().unwrap();
+        assert_eq!(db.rollbacks, vec![3]);
+        assert_eq!(db.makers(), vec![(kept, Some(3)), (replacement, Some(5))]);
+        assert_eq!(db.checkpoint_tip(), Some((6, tip)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reindexes_window_on_reorg_deeper_than_window() {
+        let chain = FakeChain::new();
+        chain.mine_empty(10);
+        let (below_window, tx) = announcement(1);
+        chain.mine(vec![tx]);
+        chain.mine_empty(100);
+        let (in_window, tx) = announcement(2);
+        chain.mine(vec![tx]);
+        chain.mine_empty(20);
+        let (db, _shutdown) = start(&chain, false).await;
+        sleep(ROUND).await;
+        let old_tip = chain.height();
+        assert_eq!(
+            db.lock().unwrap().makers(),
+            vec![(below_window.clone(), Some(11)), (in_window, Some(112))]
+        );
+
+        chain.fork(5);
+        let (deep, tx) = announcement(3);
+        chain.mine(vec![tx]);
+        chain.mine_empty(old_tip);
+        let tip = chain.mine(Vec::new());
+        sleep(ROUND).await;
+
+        // The fork point is out of reach, so everything the window covers is indexed again from
+        // its oldest block. What lies below it, on either branch, stays as it was.
+        let oldest = old_tip - REORG_WINDOW as u64 + 1;
+        let db = db.lock().unwrap();
+        assert_eq!(db.rollbacks, vec![oldest - 1]);
+        assert_eq!(db.makers(), vec![(below_window, Some(11))]);
+        assert!(!db.makers.contains_key(&deep));
+        assert_eq!(db.checkpoint_tip(), Some((chain.height(), tip)));
     }
-    matches!(port.parse::<u16>(), Ok(p) if p > 0)
 }
@@ -1,77 +1,153 @@
 #![allow(dead_code)]
-use std::path::Path;
+use std::{path::PathBuf, time::Duration};
 
 use bitcoincore_rpc::Auth;
-use bitcoincore_rpc::Client;
 use clap::Parser;
+use config::{Network, Settings};
+use db::RankingKind;
 use error::TrackerError;
-use status::{State, Status};
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use indexer::BitcoinRpc;
+use supervisor::{ComponentConfig, Supervisor};
+use tokio::{
+    sync::oneshot,
+    time::{sleep, timeout},
+};
+use tokio_graceful::Shutdown;
 use tor::check_tor_status;
 use tor::get_tor_hostname;
+use tor::remove_onion_service;
 use tracing::error;
 use tracing::{info, warn};
-use types::DbRequest;
+mod config;
 mod db;
 mod error;
+mod fidelity;
 mod handle_error;
 mod indexer;
 mod server;
 mod status;
+mod supervisor;
 mod tor;
 mod types;
 mod utils;
 
+/// Every setting can also come from a `TRACKER_*` environment variable or from `tracker.toml` in
+/// the datadir; flags win over the environment, which wins over the file. Anything left unset
+/// falls back to the `--network` profile.
 #[derive(Parser)]
 #[clap(version = option_env ! ("CARGO_PKG_VERSION").unwrap_or("unknown"),
 author = option_env ! ("CARGO_PKG_AUTHORS").unwrap_or(""))]
 struct App {
-    #[clap(
-        name = "ADDRESS:PORT",
-        long,
-        short = 'r',
-        default_value = "127.0.0.1:48332"
-    )]
-    pub(crate) rpc: String,
+    /// Network to run on, which picks the default RPC port, start height and datadir subfolder
+    /// [default: regtest, in the base datadir].
+    #[clap(long, value_enum, env = "TRACKER_NETWORK")]
+    pub network: Option<Network>,
+
+    #[clap(name = "ADDRESS:PORT", long, short = 'r', env = "TRACKER_RPC")]
+    pub(crate) rpc: Option<String>,
     #[clap(
         name = "USER:PASSWORD",
         short = 'a',
         long,
         value_parser = parse_proxy_auth,
-        default_value = "username:password",
+        env = "TRACKER_AUTH",
     )]
-    pub auth: (String, String),
+    pub auth: Option<(String, String)>,
+
+    /// Authenticate to bitcoind with its `.cookie` file instead of a password. The file is read
+    /// again whenever bitcoind restarts.
+    #[clap(long, env = "TRACKER_RPC_COOKIE", conflicts_with = "USER:PASSWORD")]
+    pub rpc_cookie: Option<PathBuf>,
     #[clap(
         name = "Server ADDRESS:PORT",
         short = 's',
         long,
-        default_value = "127.0.0.1:8080"
+        env = "TRACKER_ADDRESS"
     )]
-    pub address: String,
+    pub address: Option<String>,
 
-    #[clap(name = "control port PORT", short = 'c', long, default_value = "9051")]
-    pub control_port: u16,
+    #[clap(
+        name = "control port PORT",
+        short = 'c',
+        long,
+        env = "TRACKER_CONTROL_PORT"
+    )]
+    pub control_port: Option<u16>,
 
-    #[clap(name = "tor_auth_password", long, default_value = "")]
-    pub tor_auth_password: String,
+    #[clap(name = "tor_auth_password", long, env = "TRACKER_TOR_AUTH_PASSWORD")]
+    pub tor_auth_password: Option<String>,
 
-    #[clap(name = "socks port PORT", long, default_value = "9050")]
-    pub socks_port: u16,
+    #[clap(name = "socks port PORT", long, env = "TRACKER_SOCKS_PORT")]
+    pub socks_port: Option<u16>,
 
-    #[clap(name = "datadir", long, default_value = ".tracker")]
+    /// Base directory for tracker state; networks other than mainnet use a subfolder of it.
+    #[clap(
+        name = "datadir",
+        long,
+        default_value = ".tracker",
+        env = "TRACKER_DATADIR"
+    )]
     pub datadir: String,
+
+    /// Height to start indexing from when no checkpoint is stored, or when the stored one is older.
+    #[clap(name = "start height", long, env = "TRACKER_START_HEIGHT")]
+    pub start_height: Option<u64>,
+
+    /// Only index confirmed announcements, ignoring the mempool. `--no-mempool=false` scans it
+    /// even if the config file says otherwise.
+    #[clap(
+        long,
+        env = "TRACKER_NO_MEMPOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub no_mempool: Option<bool>,
+
+    /// Most maker liveness probes in flight at once [default: 16].
+    #[clap(
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        env = "TRACKER_PROBE_PARALLELISM"
+    )]
+    pub probe_parallelism: Option<usize>,
+
+    /// Seconds between liveness probes of a healthy maker [default: 300].
+    #[clap(long, env = "TRACKER_PROBE_INTERVAL")]
+    pub probe_interval: Option<u64>,
+
+    /// Seconds between checks for makers that are due a probe [default: 10].
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), env = "TRACKER_PROBE_TICK")]
+    pub probe_tick: Option<u64>,
+
+    /// Connection attempts making up one probe [default: 3].
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), env = "TRACKER_PROBE_ATTEMPTS")]
+    pub probe_attempts: Option<u32>,
+
+    /// Seconds to wait between the attempts of a probe [default: 1].
+    #[clap(long, env = "TRACKER_PROBE_RETRY_DELAY")]
+    pub probe_retry_delay: Option<u64>,
+
+    /// Upper bound, in seconds, on the backed-off probe interval of a failing maker
+    /// [default: 21600].
+    #[clap(long, env = "TRACKER_MAX_PROBE_BACKOFF")]
+    pub max_probe_backoff: Option<u64>,
+
+    /// Remove a maker from the registry after this many failed probes in a row.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), env = "TRACKER_EVICT_AFTER")]
+    pub evict_after: Option<u32>,
+
+    /// Order in which active makers are returned to takers [default: bond-value].
+    #[clap(long, value_enum, env = "TRACKER_RANKING")]
+    pub ranking: Option<RankingKind>,
 }
 
 fn parse_proxy_auth(s: &str) -> Result<(String, String), TrackerError> {
-    let parts: Vec<_> = s.split(':').collect();
-    if parts.len() != 2 {
-        return Err(TrackerError::ParsingError);
+    // Only the user name is split off, since the password may itself contain colons.
+    match s.split_once(':') {
+        Some((user, passwd)) if !user.is_empty() => Ok((user.to_string(), passwd.to_string())),
+        _ => Err(TrackerError::ParsingError),
     }
-
-    let user = parts[0].to_string();
-    let passwd = parts[1].to_string();
-
-    Ok((user, passwd))
 }
 
 #[derive(Debug, Clone)]
@@ -80,7 +156,11 @@ pub struct RPCConfig {
     pub auth: Auth,
 }
 
-const RPC_HOSTPORT: &str = "localhost:18443";
+/// How long in-flight work gets to wind down after a shutdown signal.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long Tor gets to drop the hidden service on the way out, so a hung control port can't stall
+/// the exit.
+const ONION_REMOVAL_TIMEOUT: Duration = Duration::from_secs(10);
 
 impl RPCConfig {
     fn new(url: String, auth: Auth) -> Self {
@@ -88,18 +168,21 @@ impl RPCConfig {
     }
 }
 
-impl Default for RPCConfig {
-    fn default() -> Self {
-        Self {
-            url: RPC_HOSTPORT.to_string(),
-            auth: Auth::UserPass("regtestrpcuser".to_string(), "regtestrpcpass".to_string()),
-        }
-    }
-}
+/// How often to check on a node that is still syncing.
+const NODE_SYNC_POLL: Duration = Duration::from_secs(30);
 
-impl From<RPCConfig> for Client {
-    fn from(value: RPCConfig) -> Self {
-        Client::new(&value.url, value.auth.clone()).unwrap()
+/// Waits for bitcoind to catch up with the chain, failing if it is on a different network than
+/// the tracker.
+async fn wait_for_node(rpc: &BitcoinRpc, network: Network) -> Result<(), TrackerError> {
+    loop {
+        if rpc.check_node(network.chain()).await? {
+            return Ok(());
+        }
+        info!("Waiting for bitcoind to finish syncing");
+        tokio::select! {
+            _ = sleep(NODE_SYNC_POLL) => {}
+            _ = tokio_graceful::default_signal() => return Err(TrackerError::Shutdown),
+        }
     }
 }
 
@@ -107,22 +190,41 @@ impl From<RPCConfig> for Client {
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let args = App::parse();
+    let settings = match Settings::resolve(App::parse()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            return;
+        }
+    };
+    info!(
+        "Running on {:?} with datadir {}",
+        settings.network,
+        settings.datadir.display()
+    );
 
-    let rpc_config = RPCConfig::new(args.rpc, Auth::UserPass(args.auth.0, args.auth.1));
+    let rpc_config = RPCConfig::new(settings.rpc.clone(), settings.auth.clone());
+    let node_ready = match BitcoinRpc::new(rpc_config.clone()) {
+        Ok(rpc) => wait_for_node(&rpc, settings.network).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = node_ready {
+        error!("Bitcoin node is not usable: {}", e);
+        return;
+    }
 
-    check_tor_status(args.control_port, &args.tor_auth_password)
+    check_tor_status(settings.control_port, &settings.tor_auth_password)
         .await
         .expect("Failed to check Tor status");
 
-    let hostname = match args.address.split_once(':') {
+    let hostname = match settings.address.split_once(':') {
         Some((_, port)) => {
             let port = port.parse::<u16>().expect("Invalid port in address");
             get_tor_hostname(
-                Path::new(&args.datadir),
-                args.control_port,
+                &settings.datadir,
+                settings.control_port,
                 port,
-                &args.tor_auth_password,
+                &settings.tor_auth_password,
             )
             .await
             .expect("Failed to retrieve Tor hostname")
@@ -135,85 +237,51 @@ async fn main() {
 
     info!("Tracker is listening at {}", hostname);
 
-    let (mut db_tx, db_rx) = mpsc::channel::<DbRequest>(10);
-    let (status_tx, mut status_rx) = mpsc::channel::<Status>(10);
-
-    let server_address = args.address.clone();
+    // Besides the usual signals, shut down when the supervisor gives up on a component.
+    let (fatal_tx, fatal_rx) = oneshot::channel::<()>();
+    let shutdown = Shutdown::new(async move {
+        tokio::select! {
+            _ = tokio_graceful::default_signal() => {}
+            _ = fatal_rx => {}
+        }
+    });
 
-    spawn_db_manager(db_rx, status_tx.clone()).await;
-    spawn_mempool_indexer(db_tx.clone(), status_tx.clone(), rpc_config.clone().into()).await;
-    spawn_server(
-        db_tx.clone(),
-        status_tx.clone(),
-        server_address.clone(),
-        args.socks_port,
-    )
-    .await;
+    let supervisor = Supervisor::new(ComponentConfig {
+        datadir: settings.datadir.clone(),
+        ranking: settings.ranking,
+        rpc_config,
+        indexer: settings.indexer,
+        server_address: settings.address.clone(),
+        monitor: settings.monitor,
+    });
 
     info!("Tracker started");
 
-    while let Some(status) = status_rx.recv().await {
-        match status.state {
-            State::DBShutdown(err) => {
-                warn!(
-                    "DB Manager exited unexpectedly. Restarting... Error: {:?}",
-                    err
-                );
-                let (new_db_tx, new_db_rx) = mpsc::channel::<DbRequest>(10);
-                db_tx = new_db_tx;
-                spawn_db_manager(new_db_rx, status_tx.clone()).await;
-            }
-            State::Healthy(info) => {
-                info!("System healthy: {:?}", info);
-            }
-            State::MempoolShutdown(err) => {
-                warn!("Mempool Indexer crashed. Restarting... Error: {:?}", err);
-                let client: Client = rpc_config.clone().into();
-                spawn_mempool_indexer(db_tx.clone(), status_tx.clone(), client).await;
-            }
-            State::ServerShutdown(err) => {
-                warn!("Server crashed. Restarting... Error: {:?}", err);
-                spawn_server(
-                    db_tx.clone(),
-                    status_tx.clone(),
-                    server_address.clone(),
-                    args.socks_port,
-                )
-                .await;
-            }
-        }
+    if let Err(e) = supervisor.run(&shutdown).await {
+        error!("{}", e);
+        let _ = fatal_tx.send(());
     }
-}
-
-async fn spawn_db_manager(db_tx: Receiver<DbRequest>, status_tx: Sender<Status>) {
-    info!("Spawning db manager");
-    tokio::spawn(db::run(db_tx, status::Sender::DBManager(status_tx)));
-}
 
-async fn spawn_mempool_indexer(
-    db_tx: Sender<DbRequest>,
-    status_tx: Sender<Status>,
-    client: Client,
-) {
-    info!("Spawning indexer");
-    tokio::spawn(indexer::run(
-        db_tx,
-        status::Sender::Mempool(status_tx),
-        client.into(),
-    ));
-}
+    info!("Shutting down tracker");
+    // The supervisor is gone along with its DB handle, so the DB manager exits, flushing the
+    // registry, once the other components have let go of theirs.
+    match shutdown.shutdown_with_limit(SHUTDOWN_TIMEOUT).await {
+        Ok(elapsed) => info!("All components stopped after {:?}", elapsed),
+        Err(e) => warn!("Components did not stop in time: {}", e),
+    }
 
-async fn spawn_server(
-    db_tx: Sender<DbRequest>,
-    status_tx: Sender<Status>,
-    address: String,
-    socks_port: u16,
-) {
-    info!("Spawning server instance");
-    tokio::spawn(server::run(
-        db_tx,
-        status::Sender::Server(status_tx),
-        address,
-        socks_port,
-    ));
+    let removal = remove_onion_service(
+        settings.control_port,
+        &settings.tor_auth_password,
+        &hostname,
+    );
+    match timeout(ONION_REMOVAL_TIMEOUT, removal).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("Failed to remove Tor Hidden Service: {:?}", e),
+        Err(_) => warn!(
+            "Tor did not remove the Hidden Service within {:?}",
+            ONION_REMOVAL_TIMEOUT
+        ),
+    }
+    info!("Tracker stopped");
 }
@@ -1,3 +1,4 @@
 mod tracker_monitor;
 mod tracker_server;
+pub use tracker_monitor::{MonitorConfig, monitor_systems};
 pub use tracker_server::run;
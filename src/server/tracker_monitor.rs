@@ -1,100 +1,264 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
+use bitcoincore_rpc::bitcoin::PublicKey;
+use rand::Rng;
 use tokio::{
-    io::BufWriter,
-    sync::mpsc::Sender,
-    time::{Instant, sleep},
+    task::{Id, JoinSet},
+    time::{Instant, interval, sleep, timeout},
 };
+use tokio_graceful::ShutdownGuard;
 use tokio_socks::tcp::Socks5Stream;
 use tracing::{info, warn};
 
 use crate::{
+    db::DbHandle,
     error::TrackerError,
+    fidelity::verify_pong,
     handle_result, status,
-    types::{DbRequest, DnsRequest, DnsResponse, ServerInfo},
-    utils::{read_message, send_message},
+    types::{DbRequest, DnsRequest, DnsResponse},
+    utils::{MAX_FRAME_SIZE, message_stream, read_message, send_message},
 };
 
-use tokio::io::BufReader;
+/// Fraction by which a maker's probe interval is randomly stretched or shrunk, so probes don't
+/// bunch up.
+const PROBE_JITTER: f64 = 0.2;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const PROBE_READ_TIMEOUT: Duration = Duration::from_secs(30);
 
-const COOLDOWN_PERIOD: u64 = 5 * 60;
+#[derive(Debug, Clone, Copy)]
+pub struct MonitorConfig {
+    pub socks_port: u16,
+    /// Most probes in flight at once.
+    pub parallelism: usize,
+    /// How often a healthy maker is probed, give or take `PROBE_JITTER`.
+    pub probe_interval: Duration,
+    /// How often the registry is checked for makers that are due.
+    pub schedule_tick: Duration,
+    /// Connection attempts making up one probe.
+    pub attempts: u32,
+    /// Pause between the attempts of a probe.
+    pub retry_delay: Duration,
+    /// Longest a failing maker's probe interval is allowed to grow to.
+    pub max_backoff: Duration,
+    /// Consecutive failed probes after which a maker is removed from the registry, if set.
+    pub evict_after: Option<u32>,
+}
+
+impl MonitorConfig {
+    /// Time until the next probe of a maker whose last `failures` probes failed in a row: the
+    /// probe interval, doubled for every failure up to `max_backoff`.
+    fn probe_delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return self.probe_interval;
+        }
+        self.probe_interval
+            .saturating_mul(1 << failures.min(16))
+            .min(self.max_backoff.max(self.probe_interval))
+    }
+}
+
+/// Probes every maker on its own jittered schedule, a bounded number at a time.
 pub async fn monitor_systems(
-    db_tx: Sender<DbRequest>,
+    db_tx: DbHandle,
     status_tx: status::Sender,
-    socks_port: u16,
+    config: MonitorConfig,
+    guard: ShutdownGuard,
 ) -> Result<(), TrackerError> {
     info!("Starting to monitor other maker services");
 
-    loop {
-        sleep(Duration::from_secs(1000)).await;
+    let mut makers = Vec::new();
+    let mut next_probe: HashMap<String, Instant> = HashMap::new();
+    let mut in_flight: HashMap<Id, String> = HashMap::new();
+    // Consecutive failed probes per maker. Kept in memory only, so a restart gives every maker a
+    // clean slate.
+    let mut failures: HashMap<String, u32> = HashMap::new();
+    // When each maker was last probed, or last credited for a heartbeat instead, so that only
+    // heartbeats received since count.
+    let mut probed_at: HashMap<String, Instant> = HashMap::new();
+    // Dropped, aborting any probe still running, when the monitor stops.
+    let mut probes: JoinSet<Option<Answer>> = JoinSet::new();
+    let mut ticker = interval(config.schedule_tick);
 
-        let (response_tx, mut response_rx) = tokio::sync::mpsc::channel(1);
-        if db_tx.send(DbRequest::QueryAll(response_tx)).await.is_err() {
-            continue;
-        }
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let (response_tx, mut response_rx) = tokio::sync::mpsc::channel(1);
+                handle_result!(status_tx, db_tx.send(DbRequest::QueryAll(response_tx)).await);
+                let Some(response) = response_rx.recv().await else {
+                    continue;
+                };
+                makers = response;
+                next_probe.retain(|address, _| makers.iter().any(|(a, _)| a == address));
+                failures.retain(|address, _| makers.iter().any(|(a, _)| a == address));
+                probed_at.retain(|address, _| makers.iter().any(|(a, _)| a == address));
+            }
+            Some(joined) = probes.join_next_with_id() => {
+                let (address, answer) = match joined {
+                    Ok((id, answer)) => (in_flight.remove(&id), answer),
+                    Err(e) => {
+                        warn!("Probe failed: {e}");
+                        (in_flight.remove(&e.id()), None)
+                    }
+                };
+                let Some(address) = address else {
+                    continue;
+                };
+                let failed = failures.entry(address.clone()).or_default();
+                *failed = if answer.is_some() { 0 } else { *failed + 1 };
+                let failed = *failed;
 
-        if let Some(response) = response_rx.recv().await {
-            for (address, server_info) in response {
-                let cooldown_duration = Duration::from_secs(COOLDOWN_PERIOD);
-                if server_info.cooldown.elapsed() <= cooldown_duration {
+                if config.evict_after.is_some_and(|limit| failed >= limit) {
+                    info!("Evicting {address} after {failed} failed probes in a row");
+                    failures.remove(&address);
+                    next_probe.remove(&address);
+                    probed_at.remove(&address);
+                    makers.retain(|(a, _)| *a != address);
+                    handle_result!(status_tx, db_tx.send(DbRequest::Evict(address)).await);
                     continue;
                 }
 
-                let mut success = false;
-                for attempt in 1..=3 {
-                    let connect_result = Socks5Stream::connect(
-                        format!("127.0.0.1:{socks_port:?}").as_str(),
-                        address.clone(),
-                    )
-                    .await;
-
-                    match connect_result {
-                        Ok(mut stream) => {
-                            success = true;
-
-                            let (read_half, write_half) = stream.split();
-
-                            let mut reader = BufReader::new(read_half);
-
-                            let mut writer = BufWriter::new(write_half);
-
-                            let message = DnsResponse::Ping;
-                            _ = send_message(&mut writer, &message).await;
-
-                            let buffer = handle_result!(status_tx, read_message(&mut reader).await);
-                            let response: DnsRequest =
-                                handle_result!(status_tx, serde_cbor::de::from_reader(&buffer[..]));
-
-                            if let DnsRequest::Pong { address } = response {
-                                let updated_info = ServerInfo {
-                                    onion_address: address.clone(),
-                                    cooldown: Instant::now(),
-                                    stale: false,
-                                };
-                                let _ = db_tx.send(DbRequest::Update(address, updated_info)).await;
-                            }
-
-                            break;
-                        }
-
-                        Err(e) => {
-                            warn!(
-                                "Failed to connect to {} (attempt {}/3): {}",
-                                address, attempt, e
-                            );
-                            sleep(Duration::from_secs(1)).await;
-                        }
-                    }
+                let delay = jittered(config.probe_delay(failed));
+                next_probe.insert(address.clone(), Instant::now() + delay);
+                let request = DbRequest::RecordProbe {
+                    address,
+                    latency: answer.map(|a| a.latency),
+                    rekeyed: answer.is_some_and(|a| a.rekeyed),
+                };
+                handle_result!(status_tx, db_tx.send(request).await);
+            }
+            _ = guard.cancelled() => break,
+        }
+
+        let now = Instant::now();
+        for (address, server_info) in &makers {
+            if in_flight.len() >= config.parallelism {
+                break;
+            }
+            if in_flight.values().any(|a| a == address) {
+                continue;
+            }
+            // Newly seen makers are spread over the first interval rather than probed at once.
+            let due = *next_probe
+                .entry(address.clone())
+                .or_insert_with(|| now + config.probe_interval.mul_f64(rand::random()));
+            if due > now {
+                continue;
+            }
+            // A heartbeat since the last probe already showed the maker is up, which counts
+            // towards its uptime like an answered probe.
+            let heartbeat = server_info.cooldown;
+            if probed_at
+                .get(address)
+                .is_some_and(|&probed| heartbeat > probed)
+                && heartbeat.elapsed() < config.probe_interval
+            {
+                next_probe.insert(address.clone(), heartbeat + jittered(config.probe_interval));
+                probed_at.insert(address.clone(), now);
+                failures.remove(address);
+                let request = DbRequest::RecordHeartbeat(address.clone());
+                if db_tx.send(request).await.is_err() {
+                    warn!("DB manager unavailable, cannot record heartbeat of {address}");
                 }
+                continue;
+            }
+
+            let keys = Keys {
+                current: server_info.bond.pubkey,
+                pending: server_info.pending_bond.as_ref().map(|b| b.pubkey),
+            };
+            let handle = probes.spawn(probe_with_retries(config, address.clone(), keys));
+            in_flight.insert(handle.id(), address.clone());
+            probed_at.insert(address.clone(), now);
+        }
+    }
+
+    info!("Stopped monitoring maker services");
+    Ok(())
+}
+
+/// The keys a maker's answer to a probe may be signed with.
+#[derive(Debug, Clone, Copy)]
+struct Keys {
+    /// The key of the bond the maker is registered with.
+    current: PublicKey,
+    /// The key of a bond waiting to replace it.
+    pending: Option<PublicKey>,
+}
 
-                if !success && !server_info.stale {
-                    let updated_info = ServerInfo {
-                        stale: true,
-                        ..server_info
-                    };
-                    let _ = db_tx.send(DbRequest::Update(address, updated_info)).await;
+#[derive(Debug, Clone, Copy)]
+struct Answer {
+    latency: Duration,
+    /// Signed with the pending key rather than the current one.
+    rekeyed: bool,
+}
+
+/// The first answer to a probe of `address` within the configured attempts.
+async fn probe_with_retries(config: MonitorConfig, address: String, keys: Keys) -> Option<Answer> {
+    for attempt in 1..=config.attempts {
+        match probe(config.socks_port, &address, keys).await {
+            Ok(answer) => return Some(answer),
+            Err(e) => {
+                warn!(
+                    "Failed to probe {} (attempt {}/{}): {}",
+                    address, attempt, config.attempts, e
+                );
+                if attempt < config.attempts {
+                    sleep(config.retry_delay).await;
                 }
             }
         }
     }
+    None
+}
+
+fn jittered(period: Duration) -> Duration {
+    period.mul_f64(rand::thread_rng().gen_range(1.0 - PROBE_JITTER..=1.0 + PROBE_JITTER))
+}
+
+/// Connects to `address` and checks that whoever answers holds the key of the bond registered
+/// for it, or of its pending bond, by having it sign a fresh nonce. The latency is how long the
+/// answer took, not counting the connection setup.
+async fn probe(socks_port: u16, address: &str, keys: Keys) -> Result<Answer, TrackerError> {
+    let proxy = format!("127.0.0.1:{socks_port}");
+    let connect = Socks5Stream::connect(proxy.as_str(), address);
+    let stream = timeout(CONNECT_TIMEOUT, connect)
+        .await
+        .map_err(|_| TrackerError::General("connection timed out".to_string()))?
+        .map_err(|e| TrackerError::General(format!("connection failed: {e}")))?;
+    let mut stream = message_stream(stream, MAX_FRAME_SIZE);
+
+    let nonce: [u8; 32] = rand::random();
+    let sent_at = Instant::now();
+    send_message(&mut stream, &DnsResponse::Ping { nonce }).await?;
+    let buffer = timeout(PROBE_READ_TIMEOUT, read_message(&mut stream))
+        .await
+        .map_err(|_| TrackerError::General("no answer in time".to_string()))??;
+    let latency = sent_at.elapsed();
+    let DnsRequest::Pong {
+        address: claimed,
+        signature,
+    } = serde_cbor::de::from_slice(&buffer)?
+    else {
+        return Err(TrackerError::General("expected a Pong".to_string()));
+    };
+
+    if claimed != address {
+        return Err(TrackerError::General(format!(
+            "answered as {claimed} instead"
+        )));
+    }
+    let Some(signature) = signature else {
+        return Err(TrackerError::General("Pong is not signed".to_string()));
+    };
+    let signed_by = |key: &PublicKey| verify_pong(key, &nonce, address, &signature).is_ok();
+    let rekeyed = if signed_by(&keys.current) {
+        false
+    } else if keys.pending.as_ref().is_some_and(signed_by) {
+        true
+    } else {
+        return Err(TrackerError::General(
+            "Pong is not signed by the bond key".to_string(),
+        ));
+    };
+    Ok(Answer { latency, rekeyed })
 }
@@ -1,74 +1,583 @@
-use crate::handle_result;
-use crate::server::tracker_monitor::monitor_systems;
+use std::{io, sync::Arc, time::Duration};
+
+use bitcoincore_rpc::bitcoin::Amount;
+use bitcoincore_rpc::bitcoin::secp256k1::ecdsa::Signature;
+
+use crate::db::DbHandle;
+use crate::error::TrackerError;
+use crate::fidelity::fidelity_script_pubkey;
+use crate::fidelity::verify_fidelity_proof;
+use crate::fidelity::verify_heartbeat;
+use crate::indexer::{BitcoinRpc, BlockSource, Interruptible};
 use crate::status;
+use crate::types::ClientMessage;
 use crate::types::DbRequest;
+use crate::types::DnsMetadata;
 use crate::types::DnsRequest;
 use crate::types::DnsResponse;
+use crate::types::ErrorCode;
+use crate::types::FidelityBond;
+use crate::types::Handshake;
+use crate::types::MakerFilter;
+use crate::types::MakerPage;
+use crate::types::MakerRecord;
+use crate::types::ProbeHistory;
+use crate::types::RecordPage;
+use crate::types::RejectReason;
+use crate::types::ServerInfo;
+use crate::types::ServerMessage;
+use crate::utils::MAX_FRAME_SIZE;
+use crate::utils::MessageStream;
+use crate::utils::message_stream;
 use crate::utils::read_message;
 use crate::utils::send_message;
-use tokio::io::BufReader;
-use tokio::io::BufWriter;
+use crate::utils::to_unix;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
-use tokio::sync::mpsc::Sender;
+use tokio::time::Instant;
+use tokio_graceful::ShutdownGuard;
 use tracing::info;
 
 pub async fn run(
-    db_tx: Sender<DbRequest>,
+    db_tx: DbHandle,
     status_tx: status::Sender,
     address: String,
-    socks_port: u16,
+    rpc: BitcoinRpc,
+    guard: ShutdownGuard,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let server = TcpListener::bind(&address).await?;
 
-    tokio::spawn(monitor_systems(
-        db_tx.clone(),
-        status_tx.clone(),
-        socks_port,
-    ));
-
     info!("Tracker server listening on {}", address);
 
-    while let Ok((stream, client_addr)) = server.accept().await {
+    let rpc = Arc::new(rpc);
+    loop {
+        let (stream, client_addr) = tokio::select! {
+            accepted = server.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            _ = guard.cancelled() => {
+                info!("Tracker server no longer accepting connections");
+                break;
+            }
+        };
         info!("Accepted connection from {}", client_addr);
         let status_tx_clone = status_tx.clone();
         let db_tx_clone = db_tx.clone();
-        tokio::spawn(async move { handle_client(stream, status_tx_clone, db_tx_clone).await });
+        let rpc_clone = rpc.clone();
+        guard.spawn_task_fn(move |guard| async move {
+            handle_client(stream, status_tx_clone, db_tx_clone, rpc_clone, guard).await
+        });
     }
 
     Ok(())
 }
 
-async fn handle_client(mut stream: TcpStream, status_tx: status::Sender, db_tx: Sender<DbRequest>) {
-    let (read_half, write_half) = stream.split();
+/// Requests a single connection may make per `RATE_WINDOW`.
+const MAX_REQUESTS_PER_WINDOW: u32 = 30;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Fixed-window request counter for one connection.
+struct RateLimiter {
+    window_start: Instant,
+    requests: u32,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        RateLimiter {
+            window_start: Instant::now(),
+            requests: 0,
+        }
+    }
+
+    /// Counts a request, returning whether it is within the limit.
+    fn allow(&mut self) -> bool {
+        if self.window_start.elapsed() >= RATE_WINDOW {
+            self.window_start = Instant::now();
+            self.requests = 0;
+        }
+        self.requests += 1;
+        self.requests <= MAX_REQUESTS_PER_WINDOW
+    }
+}
 
-    let mut reader = BufReader::new(read_half);
-    let mut writer = BufWriter::new(write_half);
+async fn handle_client(
+    stream: TcpStream,
+    status_tx: status::Sender,
+    db_tx: DbHandle,
+    rpc: Arc<BitcoinRpc>,
+    guard: ShutdownGuard,
+) {
+    let mut stream = message_stream(stream, MAX_FRAME_SIZE);
+    let mut limiter = RateLimiter::new();
+    let chain = Interruptible::new(BitcoinRpc::clone(&rpc), &guard);
+    // What was agreed on in the client's handshake, once it has shown it speaks the enveloped
+    // protocol.
+    let mut session: Option<Handshake> = None;
 
     loop {
-        let buffer = handle_result!(status_tx, read_message(&mut reader).await);
-        let request: DnsRequest =
-            handle_result!(status_tx, serde_cbor::de::from_reader(&buffer[..]));
-
-        match request {
-            DnsRequest::Get => {
-                info!("Received Get request taker");
-                let (resp_tx, mut resp_rx) = mpsc::channel(1);
-                let db_request = DbRequest::QueryActive(resp_tx);
-                handle_result!(status_tx, db_tx.send(db_request).await);
-                let response = resp_rx.recv().await;
-                if let Some(addresses) = response {
-                    let message = DnsResponse::Address { addresses };
-                    _ = send_message(&mut writer, &message).await;
-                }
+        // A request already being handled runs to completion, short of waiting on the node, but
+        // no new one is read once shutdown has started.
+        let read = tokio::select! {
+            read = read_message(&mut stream) => read,
+            _ = guard.cancelled() => break,
+        };
+        let versioned = session.is_some();
+        let buffer = match read {
+            Ok(buffer) => buffer,
+            // The frame was too large, and the stream cannot be resynchronised after it.
+            Err(TrackerError::IOError(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                report(&mut stream, versioned, ErrorCode::MalformedRequest, e).await;
+                break;
             }
-            DnsRequest::Post { metadata: _ } => {
-                todo!()
+            Err(_) => break,
+        };
+
+        // Versioned clients wrap requests in an envelope; anything else is a legacy client
+        // sending bare requests, which keeps being answered with bare responses.
+        let (id, request) = match serde_cbor::de::from_slice::<ClientMessage>(&buffer) {
+            Ok(ClientMessage::Handshake(theirs)) => {
+                let Some(agreed) = Handshake::negotiate(theirs) else {
+                    // The client speaks the enveloped protocol even if its version is refused.
+                    let message = format!("protocol version {} is not supported", theirs.version);
+                    report(&mut stream, true, ErrorCode::UnsupportedVersion, message).await;
+                    continue;
+                };
+                info!(
+                    "Negotiated protocol version {} with features {:#x}",
+                    agreed.version, agreed.features.0
+                );
+                session = Some(agreed);
+                _ = send_message(&mut stream, &ServerMessage::Handshake(agreed)).await;
+                continue;
             }
-            DnsRequest::Pong { address: _ } => {
-                todo!()
+            Ok(ClientMessage::Request { id, request }) => {
+                let refusal = match session {
+                    None => Some((ErrorCode::HandshakeRequired, "no handshake has been made")),
+                    Some(agreed) if !agreed.features.contains(request.required_features()) => {
+                        Some((
+                            ErrorCode::UnsupportedVersion,
+                            "request needs a feature the session did not negotiate",
+                        ))
+                    }
+                    Some(_) => None,
+                };
+                if let Some((code, message)) = refusal {
+                    let response = DnsResponse::error(code, message);
+                    _ = send_message(&mut stream, &ServerMessage::Response { id, response }).await;
+                    continue;
+                }
+                (Some(id), request)
+            }
+            Err(_) => match serde_cbor::de::from_slice::<DnsRequest>(&buffer) {
+                Ok(request) => (None, request),
+                Err(e) => {
+                    report(&mut stream, versioned, ErrorCode::MalformedRequest, e).await;
+                    continue;
+                }
+            },
+        };
+
+        let response = if limiter.allow() {
+            handle_request(request, &status_tx, &db_tx, &chain).await
+        } else {
+            DnsResponse::error(ErrorCode::RateLimited, "too many requests")
+        };
+        _ = match id {
+            Some(id) => send_message(&mut stream, &ServerMessage::Response { id, response }).await,
+            None => send_message(&mut stream, &response).await,
+        };
+    }
+}
+
+async fn handle_request(
+    request: DnsRequest,
+    status_tx: &status::Sender,
+    db_tx: &DbHandle,
+    chain: &impl BlockSource,
+) -> DnsResponse {
+    let result = match request {
+        DnsRequest::Get => {
+            info!("Received Get request taker");
+            query_active(db_tx)
+                .await
+                .map(|addresses| DnsResponse::Address { addresses })
+        }
+        DnsRequest::Post { metadata } => {
+            info!("Received Post request from maker: {}", metadata.url);
+            register_maker(metadata, chain, db_tx).await
+        }
+        DnsRequest::Pong { address, .. } => {
+            info!("Received unsolicited Pong from {address}");
+            Ok(DnsResponse::error(
+                ErrorCode::Unexpected,
+                "Pong is only accepted in reply to a Ping, send a Heartbeat instead",
+            ))
+        }
+        DnsRequest::Heartbeat {
+            address,
+            timestamp,
+            signature,
+        } => {
+            info!("Received Heartbeat from maker: {address}");
+            record_heartbeat(db_tx, address, timestamp, signature).await
+        }
+        DnsRequest::Query { filter } => {
+            info!("Received Query request from taker: {filter:?}");
+            let records = filter.records;
+            query_filtered(db_tx, filter).await.map(|page| {
+                if records {
+                    DnsResponse::Records { page }
+                } else {
+                    DnsResponse::Page {
+                        page: MakerPage {
+                            addresses: addresses(page.records),
+                            next_cursor: page.next_cursor,
+                        },
+                    }
+                }
+            })
+        }
+        DnsRequest::Sample {
+            count,
+            seed,
+            records,
+        } => {
+            info!("Received Sample request from taker: count: {count}");
+            query_sample(db_tx, count as usize, seed)
+                .await
+                .map(|sample| {
+                    if records {
+                        DnsResponse::Records {
+                            page: RecordPage {
+                                records: sample,
+                                next_cursor: None,
+                            },
+                        }
+                    } else {
+                        DnsResponse::Address {
+                            addresses: addresses(sample),
+                        }
+                    }
+                })
+        }
+    };
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            status::handle_error(status_tx, e).await;
+            DnsResponse::error(ErrorCode::Internal, "internal error")
+        }
+    }
+}
+
+/// Reports an error not tied to a request, in whichever framing the client speaks.
+async fn report<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut MessageStream<T>,
+    versioned: bool,
+    code: ErrorCode,
+    message: impl ToString,
+) {
+    let message = message.to_string();
+    info!("Reporting {code:?} to client: {message}");
+    _ = if versioned {
+        send_message(stream, &ServerMessage::Error { code, message }).await
+    } else {
+        send_message(stream, &DnsResponse::Error { code, message }).await
+    };
+}
+
+async fn query_filtered(db_tx: &DbHandle, filter: MakerFilter) -> Result<RecordPage, TrackerError> {
+    let (resp_tx, mut resp_rx) = mpsc::channel(1);
+    db_tx
+        .send(DbRequest::QueryFiltered(filter, resp_tx))
+        .await?;
+    resp_rx.recv().await.ok_or(TrackerError::DbManagerExited)
+}
+
+async fn query_sample(
+    db_tx: &DbHandle,
+    count: usize,
+    seed: Option<u64>,
+) -> Result<Vec<MakerRecord>, TrackerError> {
+    let (resp_tx, mut resp_rx) = mpsc::channel(1);
+    let request = DbRequest::QuerySample {
+        count,
+        seed,
+        resp_tx,
+    };
+    db_tx.send(request).await?;
+    resp_rx.recv().await.ok_or(TrackerError::DbManagerExited)
+}
+
+fn addresses(records: Vec<MakerRecord>) -> Vec<String> {
+    records.into_iter().map(|record| record.address).collect()
+}
+
+async fn query_active(db_tx: &DbHandle) -> Result<Vec<String>, TrackerError> {
+    let (resp_tx, mut resp_rx) = mpsc::channel(1);
+    db_tx.send(DbRequest::QueryActive(resp_tx)).await?;
+    resp_rx.recv().await.ok_or(TrackerError::DbManagerExited)
+}
+
+/// Verifies a heartbeat against the maker's registered bond key and, if it holds, marks the
+/// maker live, which also holds off the monitor's next probe.
+async fn record_heartbeat(
+    db_tx: &DbHandle,
+    address: String,
+    timestamp: u64,
+    signature: Signature,
+) -> Result<DnsResponse, TrackerError> {
+    let (resp_tx, mut resp_rx) = mpsc::channel(1);
+    db_tx
+        .send(DbRequest::Query(address.clone(), resp_tx))
+        .await?;
+    let Some(info) = resp_rx.recv().await.ok_or(TrackerError::DbManagerExited)? else {
+        return Ok(DnsResponse::error(
+            ErrorCode::UnknownMaker,
+            format!("{address} is not registered"),
+        ));
+    };
+
+    let now = to_unix(Instant::now());
+    if let Err(reason) = verify_heartbeat(&info.bond.pubkey, &address, timestamp, &signature, now) {
+        info!("Rejected heartbeat from {address}: {reason:?}");
+        return Ok(DnsResponse::error(
+            ErrorCode::InvalidProof(reason),
+            "heartbeat does not verify",
+        ));
+    }
+
+    db_tx.send(DbRequest::MarkLive(address)).await?;
+    Ok(DnsResponse::Accepted)
+}
+
+/// Verifies a maker's fidelity proof and, if it holds, adds or refreshes the maker's entry.
+async fn register_maker(
+    metadata: DnsMetadata,
+    chain: &impl BlockSource,
+    db_tx: &DbHandle,
+) -> Result<DnsResponse, TrackerError> {
+    let DnsMetadata { url, proof } = metadata;
+    let tip_height = chain.tip_height().await?;
+
+    if let Err(reason) = verify_fidelity_proof(&proof, &url, tip_height) {
+        info!("Rejected maker {url}: {reason:?}");
+        return Ok(DnsResponse::error(
+            ErrorCode::InvalidProof(reason),
+            "fidelity proof does not verify",
+        ));
+    }
+
+    let bond = proof.bond;
+    let Some(utxo) = chain.utxo(bond.outpoint).await? else {
+        info!("Rejected maker {url}: bond {} not found", bond.outpoint);
+        return Ok(DnsResponse::error(
+            ErrorCode::InvalidProof(RejectReason::BondNotFound),
+            format!("bond {} is not a confirmed, unspent output", bond.outpoint),
+        ));
+    };
+    if utxo.value != bond.amount
+        || utxo.script_pubkey != fidelity_script_pubkey(bond.lock_time, &bond.pubkey)
+    {
+        info!(
+            "Rejected maker {url}: bond {} does not match",
+            bond.outpoint
+        );
+        return Ok(DnsResponse::error(
+            ErrorCode::InvalidProof(RejectReason::BondMismatch),
+            format!("bond {} does not match the proof", bond.outpoint),
+        ));
+    }
+
+    let conf_height = (tip_height + 1).saturating_sub(u64::from(utxo.confirmations));
+    let server_info = ServerInfo {
+        onion_address: url.clone(),
+        cooldown: Instant::now(),
+        stale: false,
+        live_since: Instant::now(),
+        last_ping: None,
+        found_height: None,
+        unconfirmed: false,
+        bond: FidelityBond {
+            conf_height: Some(conf_height as u32),
+            ..bond
+        },
+        pending_bond: None,
+        bond_value: Amount::ZERO,
+        history: ProbeHistory::default(),
+    };
+    let outpoint = server_info.bond.outpoint;
+    let (resp_tx, mut resp_rx) = mpsc::channel(1);
+    db_tx
+        .send(DbRequest::Register(url.clone(), server_info, resp_tx))
+        .await?;
+    let reason = match resp_rx.recv().await.ok_or(TrackerError::DbManagerExited)? {
+        Ok(()) => {
+            info!("Registered maker {url} with bond {outpoint}");
+            return Ok(DnsResponse::Accepted);
+        }
+        Err(reason) => reason,
+    };
+    info!("Rejected maker {url}: {reason:?}");
+    let message = match reason {
+        RejectReason::AddressTaken => {
+            "address is registered under another bond key; this bond takes over once the maker \
+             answers a probe signed with its key"
+        }
+        RejectReason::BondInUse => "bond already backs another address",
+        _ => "registration refused",
+    };
+    Ok(DnsResponse::error(ErrorCode::InvalidProof(reason), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use bitcoincore_rpc::bitcoin::{
+        OutPoint, PublicKey, Txid,
+        absolute::LockTime,
+        hashes::Hash,
+        secp256k1::{Secp256k1, SecretKey},
+    };
+
+    use super::*;
+    use crate::indexer::fake_chain::{FakeChain, maker_announcement, maker_proof};
+
+    const URL: &str = "maker.onion:6102";
+
+    /// The registrations a fake DB manager was sent.
+    type Registered = Arc<Mutex<Vec<(String, ServerInfo)>>>;
+
+    /// A DB manager that takes every registration, keeping what it was sent.
+    fn registry() -> (DbHandle, Registered) {
+        let (db_tx, mut db_rx) = mpsc::channel(16);
+        let registered = Arc::new(Mutex::new(Vec::new()));
+        let sink = registered.clone();
+        tokio::spawn(async move {
+            while let Some(request) = db_rx.recv().await {
+                if let DbRequest::Register(address, info, resp_tx) = request {
+                    sink.lock().unwrap().push((address, info));
+                    let _ = resp_tx.send(Ok(())).await;
+                }
             }
+        });
+        (DbHandle::new(db_tx), registered)
+    }
+
+    /// Mines a bond locked until `lock_time` for key 1, returning the key and the bond as the
+    /// maker would present it.
+    fn mine_bond(chain: &FakeChain, lock_time: LockTime) -> (SecretKey, FidelityBond) {
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let pubkey = PublicKey::new(key.public_key(&Secp256k1::new()));
+        let funding = OutPoint::new(Txid::from_byte_array([1; 32]), 0);
+        let tx = maker_announcement(funding, URL, &pubkey, Amount::ONE_BTC, lock_time);
+        let outpoint = OutPoint::new(tx.compute_txid(), 0);
+        chain.mine(vec![tx]);
+        let bond = FidelityBond {
+            outpoint,
+            amount: Amount::ONE_BTC,
+            lock_time,
+            pubkey,
+            conf_height: None,
+            cert_expiry: Some(10),
+        };
+        (key, bond)
+    }
+
+    fn post(bond: FidelityBond, key: &SecretKey) -> DnsMetadata {
+        DnsMetadata {
+            url: URL.to_string(),
+            proof: maker_proof(bond, URL, key),
         }
     }
+
+    fn rejection(response: DnsResponse) -> Option<RejectReason> {
+        match response {
+            DnsResponse::Error {
+                code: ErrorCode::InvalidProof(reason),
+                ..
+            } => Some(reason),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn registers_maker_with_bond_on_chain() {
+        let chain = FakeChain::new();
+        chain.mine_empty(5);
+        let (key, bond) = mine_bond(&chain, LockTime::from_height(500_000).unwrap());
+        chain.mine_empty(2);
+        let (db_tx, registered) = registry();
+
+        let response = register_maker(post(bond.clone(), &key), &chain, &db_tx)
+            .await
+            .unwrap();
+        assert!(matches!(response, DnsResponse::Accepted));
+        let registered = registered.lock().unwrap();
+        let [(address, info)] = registered.as_slice() else {
+            panic!("expected one registration, got {registered:?}");
+        };
+        assert_eq!(address, URL);
+        assert_eq!(info.bond.outpoint, bond.outpoint);
+        assert_eq!(info.bond.conf_height, Some(6));
+    }
+
+    #[tokio::test]
+    async fn rejects_bond_not_on_chain() {
+        let chain = FakeChain::new();
+        let (key, bond) = mine_bond(&FakeChain::new(), LockTime::from_height(500_000).unwrap());
+        let (db_tx, registered) = registry();
+
+        let response = register_maker(post(bond, &key), &chain, &db_tx)
+            .await
+            .unwrap();
+        assert_eq!(rejection(response), Some(RejectReason::BondNotFound));
+        assert!(registered.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_bond_that_does_not_match_output() {
+        let chain = FakeChain::new();
+        let lock_time = LockTime::from_height(500_000).unwrap();
+        let (key, bond) = mine_bond(&chain, lock_time);
+        let (db_tx, registered) = registry();
+
+        let inflated = FidelityBond {
+            amount: Amount::from_btc(2.0).unwrap(),
+            ..bond.clone()
+        };
+        let response = register_maker(post(inflated, &key), &chain, &db_tx)
+            .await
+            .unwrap();
+        assert_eq!(rejection(response), Some(RejectReason::BondMismatch));
+
+        let relocked = FidelityBond {
+            lock_time: LockTime::from_height(600_000).unwrap(),
+            ..bond
+        };
+        let response = register_maker(post(relocked, &key), &chain, &db_tx)
+            .await
+            .unwrap();
+        assert_eq!(rejection(response), Some(RejectReason::BondMismatch));
+        assert!(registered.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_bond_that_was_never_locked() {
+        let chain = FakeChain::new();
+        let (key, bond) = mine_bond(&chain, LockTime::ZERO);
+        let (db_tx, registered) = registry();
+
+        let response = register_maker(post(bond, &key), &chain, &db_tx)
+            .await
+            .unwrap();
+        assert_eq!(rejection(response), Some(RejectReason::BondNotLocked));
+        assert!(registered.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,318 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use futures::{FutureExt, StreamExt, future::BoxFuture, stream::FuturesUnordered};
+use tokio::{
+    sync::mpsc,
+    time::{Instant, sleep},
+};
+use tokio_graceful::Shutdown;
+use tracing::{error, info, warn};
+
+use crate::{
+    RPCConfig,
+    db::{self, DbHandle, RankingKind},
+    error::TrackerError,
+    indexer::{self, BitcoinRpc, IndexerConfig},
+    server::{self, MonitorConfig},
+    status::{self, State, Status},
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Consecutive restarts allowed before a component is given up on.
+const MAX_RESTARTS: u32 = 10;
+/// A component that ran at least this long before exiting is considered to have recovered,
+/// and gets its full restart budget back.
+const STABLE_PERIOD: Duration = Duration::from_secs(5 * 60);
+const DB_CHANNEL_SIZE: usize = 10;
+const STATUS_CHANNEL_SIZE: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    DbManager,
+    Indexer,
+    Server,
+    Monitor,
+}
+
+const COMPONENTS: [Component; 4] = [
+    Component::DbManager,
+    Component::Indexer,
+    Component::Server,
+    Component::Monitor,
+];
+
+impl fmt::Display for Component {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Component::DbManager => write!(f, "db-manager"),
+            Component::Indexer => write!(f, "indexer"),
+            Component::Server => write!(f, "server"),
+            Component::Monitor => write!(f, "monitor"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComponentState {
+    Running,
+    /// Waiting out its backoff before the given restart attempt.
+    Restarting {
+        attempt: u32,
+        delay: Duration,
+    },
+    /// Exhausted its restart budget.
+    Failed,
+}
+
+impl fmt::Display for ComponentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ComponentState::Running => write!(f, "running"),
+            ComponentState::Restarting { attempt, delay } => {
+                write!(f, "restarting (attempt {attempt} in {delay:?})")
+            }
+            ComponentState::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// Everything needed to (re)spawn the tracker components.
+pub struct ComponentConfig {
+    pub datadir: PathBuf,
+    pub ranking: RankingKind,
+    pub rpc_config: RPCConfig,
+    pub indexer: IndexerConfig,
+    pub server_address: String,
+    pub monitor: MonitorConfig,
+}
+
+struct Supervised {
+    component: Component,
+    state: ComponentState,
+    restarts: u32,
+    started_at: Instant,
+}
+
+/// Spawns the tracker components and restarts them, with exponential backoff, when they exit.
+///
+/// Components reach the DB manager through a shared [`DbHandle`], so restarting it does not
+/// strand the others on a dead channel.
+pub struct Supervisor {
+    config: ComponentConfig,
+    db: DbHandle,
+    status_tx: mpsc::Sender<Status>,
+    status_rx: mpsc::Receiver<Status>,
+    components: Vec<Supervised>,
+}
+
+impl Supervisor {
+    pub fn new(config: ComponentConfig) -> Self {
+        // Replaced by the DB manager's real sender when it is spawned.
+        let (db_tx, _) = mpsc::channel(1);
+        let (status_tx, status_rx) = mpsc::channel(STATUS_CHANNEL_SIZE);
+        let components = COMPONENTS
+            .into_iter()
+            .map(|component| Supervised {
+                component,
+                state: ComponentState::Running,
+                restarts: 0,
+                started_at: Instant::now(),
+            })
+            .collect();
+        Supervisor {
+            config,
+            db: DbHandle::new(db_tx),
+            status_tx,
+            status_rx,
+            components,
+        }
+    }
+
+    pub fn states(&self) -> Vec<(Component, ComponentState)> {
+        self.components
+            .iter()
+            .map(|s| (s.component, s.state))
+            .collect()
+    }
+
+    /// Supervises the components until shutdown, or until one of them exhausts its restart
+    /// budget, which is returned as an error.
+    pub async fn run(mut self, shutdown: &Shutdown) -> Result<(), TrackerError> {
+        let mut running = FuturesUnordered::new();
+        let mut pending = FuturesUnordered::new();
+        for component in COMPONENTS {
+            running.push(self.spawn(shutdown, component));
+        }
+        self.report();
+
+        let guard = shutdown.guard_weak();
+        loop {
+            tokio::select! {
+                _ = guard.cancelled() => break,
+                Some(component) = running.next() => {
+                    let Some(delay) = self.on_exit(component) else {
+                        self.report();
+                        return Err(TrackerError::General(format!(
+                            "{component} exceeded its restart budget"
+                        )));
+                    };
+                    pending.push(async move {
+                        sleep(delay).await;
+                        component
+                    });
+                    self.report();
+                }
+                Some(component) = pending.next() => {
+                    running.push(self.spawn(shutdown, component));
+                    self.report();
+                }
+                Some(status) = self.status_rx.recv() => log_status(status),
+            }
+        }
+        Ok(())
+    }
+
+    fn supervised(&mut self, component: Component) -> &mut Supervised {
+        self.components
+            .iter_mut()
+            .find(|s| s.component == component)
+            .expect("every component is supervised")
+    }
+
+    /// Records that `component` exited and returns how long to wait before restarting it, or
+    /// `None` if it has run out of restarts.
+    fn on_exit(&mut self, component: Component) -> Option<Duration> {
+        let supervised = self.supervised(component);
+        if supervised.started_at.elapsed() >= STABLE_PERIOD {
+            supervised.restarts = 0;
+        }
+        supervised.restarts += 1;
+        if supervised.restarts > MAX_RESTARTS {
+            error!("{component} exited {MAX_RESTARTS} times in a row, giving up");
+            supervised.state = ComponentState::Failed;
+            return None;
+        }
+
+        let attempt = supervised.restarts;
+        let delay = INITIAL_BACKOFF
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(MAX_BACKOFF);
+        warn!("{component} exited, restarting in {delay:?} (attempt {attempt}/{MAX_RESTARTS})");
+        supervised.state = ComponentState::Restarting { attempt, delay };
+        Some(delay)
+    }
+
+    /// Spawns `component`, returning a future that resolves to it once it exits.
+    fn spawn(
+        &mut self,
+        shutdown: &Shutdown,
+        component: Component,
+    ) -> BoxFuture<'static, Component> {
+        info!("Spawning {component}");
+        let supervised = self.supervised(component);
+        supervised.state = ComponentState::Running;
+        supervised.started_at = Instant::now();
+
+        let config = &self.config;
+        let status_tx = self.status_tx.clone();
+        match component {
+            Component::DbManager => {
+                let (db_tx, db_rx) = mpsc::channel(DB_CHANNEL_SIZE);
+                self.db.replace(db_tx);
+                let handle = shutdown.spawn_task(db::run(
+                    db_rx,
+                    status::Sender::DBManager(status_tx),
+                    config.datadir.clone(),
+                    config.ranking,
+                ));
+                watch(component, handle.map(|res| res.map(Ok::<(), String>)))
+            }
+            Component::Indexer => {
+                let db_tx = self.db.clone();
+                let rpc_config = config.rpc_config.clone();
+                let indexer_config = config.indexer;
+                let handle = shutdown.spawn_task_fn(move |guard| async move {
+                    let rpc = BitcoinRpc::new(rpc_config).map_err(|e| e.to_string())?;
+                    indexer::run(
+                        db_tx,
+                        status::Sender::Mempool(status_tx),
+                        rpc,
+                        indexer_config,
+                        guard,
+                    )
+                    .await;
+                    Ok::<(), String>(())
+                });
+                watch(component, handle)
+            }
+            Component::Server => {
+                let db_tx = self.db.clone();
+                let rpc_config = config.rpc_config.clone();
+                let address = config.server_address.clone();
+                let handle = shutdown.spawn_task_fn(move |guard| async move {
+                    let rpc = BitcoinRpc::new(rpc_config).map_err(|e| e.to_string())?;
+                    server::run(
+                        db_tx,
+                        status::Sender::Server(status_tx),
+                        address,
+                        rpc,
+                        guard,
+                    )
+                    .await
+                    .map_err(|e| e.to_string())
+                });
+                watch(component, handle)
+            }
+            Component::Monitor => {
+                let db_tx = self.db.clone();
+                let monitor_config = config.monitor;
+                let handle = shutdown.spawn_task_fn(move |guard| async move {
+                    server::monitor_systems(
+                        db_tx,
+                        status::Sender::Server(status_tx),
+                        monitor_config,
+                        guard,
+                    )
+                    .await
+                    .map_err(|e| e.to_string())
+                });
+                watch(component, handle)
+            }
+        }
+    }
+
+    fn report(&self) {
+        let states: Vec<String> = self
+            .states()
+            .iter()
+            .map(|(component, state)| format!("{component}: {state}"))
+            .collect();
+        info!("Component states: {}", states.join(", "));
+    }
+}
+
+/// Turns a spawned component's join handle into a future that logs how it ended.
+fn watch<E: fmt::Display>(
+    component: Component,
+    handle: impl Future<Output = Result<Result<(), E>, tokio::task::JoinError>> + Send + 'static,
+) -> BoxFuture<'static, Component> {
+    async move {
+        match handle.await {
+            Ok(Ok(())) => info!("{component} stopped"),
+            Ok(Err(e)) => warn!("{component} failed: {e}"),
+            Err(e) => error!("{component} panicked: {e}"),
+        }
+        component
+    }
+    .boxed()
+}
+
+fn log_status(status: Status) {
+    match status.state {
+        State::Healthy(info) => info!("System healthy: {:?}", info),
+        State::DBShutdown(err) => warn!("DB Manager reported an error: {:?}", err),
+        State::MempoolShutdown(err) => warn!("Mempool Indexer reported an error: {:?}", err),
+        State::ServerShutdown(err) => warn!("Server reported an error: {:?}", err),
+    }
+}
@@ -100,6 +100,41 @@ pub(crate) async fn get_emphemeral_address(
     Ok((format!("{service_id}.onion"), private_key))
 }
 
+/// Removes the onion service, which outlives our control connection since it is added detached.
+pub(crate) async fn remove_onion_service(
+    control_port: u16,
+    password: &str,
+    hostname: &str,
+) -> Result<(), TrackerError> {
+    let (reader, mut writer) = TcpStream::connect(format!("127.0.0.1:{control_port}"))
+        .await?
+        .into_split();
+    let mut reader = BufReader::new(reader);
+    let mut response = String::new();
+    let auth_command = format!("AUTHENTICATE \"{password}\"\r\n");
+    writer.write_all(auth_command.as_bytes()).await?;
+    reader.read_line(&mut response).await?;
+    if !response.starts_with("250") {
+        return Err(TrackerError::General(
+            "Tor authentication failed".to_string(),
+        ));
+    }
+
+    let service_id = hostname.trim_end_matches(".onion");
+    let remove_command = format!("DEL_ONION {service_id}\r\n");
+    writer.write_all(remove_command.as_bytes()).await?;
+    response.clear();
+    reader.read_line(&mut response).await?;
+    if !response.starts_with("250") {
+        return Err(TrackerError::General(format!(
+            "Failed to remove onion service: {}",
+            response.trim()
+        )));
+    }
+    info!("Removed Tor Hidden Service {}", hostname);
+    Ok(())
+}
+
 pub(crate) async fn get_tor_hostname(
     data_dir: &Path,
     control_port: u16,
@@ -1,23 +1,150 @@
 use bitcoincore_rpc::bitcoin::{
-    Amount, OutPoint, PublicKey, absolute::LockTime, hashes::hash160::Hash,
+    Amount, BlockHash, OutPoint, PublicKey, absolute::LockTime, hashes::hash160::Hash,
     secp256k1::ecdsa::Signature,
 };
 use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, time::Duration};
+
 use tokio::{sync::mpsc::Sender, time::Instant};
 
+use crate::utils::to_unix;
+
 #[derive(Debug, Clone)]
 pub struct ServerInfo {
     pub onion_address: String,
+    /// When the maker last sent a heartbeat, or was registered if it never has.
     pub cooldown: Instant,
     pub stale: bool,
+    /// When the maker last came online: when it was first registered, or first reachable again
+    /// after going stale.
+    pub live_since: Instant,
+    /// When the maker last answered a ping or sent a heartbeat.
+    pub last_ping: Option<Instant>,
+    /// Height of the block the maker's announcement was first found in.
+    pub found_height: Option<u64>,
+    /// Set while the announcement has only been seen in the mempool.
+    pub unconfirmed: bool,
+    /// The fidelity bond backing the maker.
+    pub bond: FidelityBond,
+    /// A bond announced for this address under a different key, which replaces `bond` once the
+    /// maker answers a probe signed with that key.
+    pub pending_bond: Option<FidelityBond>,
+    /// Ranking score derived from the bond, kept up to date by the DB manager.
+    pub bond_value: Amount,
+    /// Outcomes of the most recent liveness probes.
+    pub history: ProbeHistory,
+}
+
+/// Number of probe outcomes kept per maker: a day's worth at the default probe interval.
+const HISTORY_LEN: usize = 288;
+
+/// Stands in for the latency of a maker that was up without being probed.
+const UNMEASURED: u32 = u32::MAX;
+
+/// Rolling record of a maker's most recent probes, as round-trip latencies in milliseconds, or
+/// `None` for probes it failed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProbeHistory {
+    samples: VecDeque<Option<u32>>,
+}
+
+impl ProbeHistory {
+    /// Records a probe that took `latency` to be answered, or failed if `None`.
+    pub fn record(&mut self, latency: Option<Duration>) {
+        let millis = latency.map(|l| {
+            u32::try_from(l.as_millis())
+                .unwrap_or(u32::MAX)
+                .min(UNMEASURED - 1)
+        });
+        self.push(millis);
+    }
+
+    /// Records a probe that was skipped because the maker had just sent a heartbeat. It counts
+    /// towards uptime but not latency.
+    pub fn record_heartbeat(&mut self) {
+        self.push(Some(UNMEASURED));
+    }
+
+    fn push(&mut self, sample: Option<u32>) {
+        if self.samples.len() == HISTORY_LEN {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Percentage of the recorded probes that were answered.
+    pub fn uptime(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let answered = self.samples.iter().filter(|s| s.is_some()).count();
+        Some(answered as f64 * 100.0 / self.samples.len() as f64)
+    }
+
+    /// Median round-trip latency of the answered probes.
+    pub fn median_latency(&self) -> Option<Duration> {
+        let mut latencies: Vec<u32> = self
+            .samples
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|&ms| ms != UNMEASURED)
+            .collect();
+        if latencies.is_empty() {
+            return None;
+        }
+        latencies.sort_unstable();
+        Some(Duration::from_millis(u64::from(
+            latencies[latencies.len() / 2],
+        )))
+    }
+}
+
+/// Blocks the indexer has fully processed, oldest first.
+///
+/// Only the most recent ones are kept, enough to find the fork point of a reorg after a restart.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub blocks: Vec<(u64, BlockHash)>,
 }
 
 pub enum DbRequest {
     Add(String, ServerInfo),
+    /// Like `Add`, for a maker registering itself, answering whether the registration was taken.
+    Register(String, ServerInfo, Sender<Result<(), RejectReason>>),
     Query(String, Sender<Option<ServerInfo>>),
     Update(String, ServerInfo),
     QueryAll(Sender<Vec<(String, ServerInfo)>>),
     QueryActive(Sender<Vec<String>>),
+    /// Drops every maker first found above the given height.
+    Rollback(u64),
+    /// Drops the maker if its announcement never confirmed.
+    DropUnconfirmed(String),
+    /// Drops every maker whose announcement never confirmed.
+    ClearUnconfirmed,
+    SetCheckpoint(Checkpoint),
+    QueryCheckpoint(Sender<Option<Checkpoint>>),
+    /// One page of the active makers matching the filter.
+    QueryFiltered(MakerFilter, Sender<RecordPage>),
+    /// Records that the maker was just seen alive.
+    MarkLive(String),
+    /// A probe of the maker was skipped thanks to a recent heartbeat.
+    RecordHeartbeat(String),
+    /// Records the outcome of a probe: answered after `latency`, or failed if `None`.
+    RecordProbe {
+        address: String,
+        latency: Option<Duration>,
+        /// The answer was signed with the key of the maker's pending bond, which takes over.
+        rekeyed: bool,
+    },
+    /// Removes the maker from the registry.
+    Evict(String),
+    /// A random sample of active makers, weighted by bond value.
+    QuerySample {
+        count: usize,
+        seed: Option<u64>,
+        resp_tx: Sender<Vec<MakerRecord>>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, PartialOrd, Hash)]
@@ -62,12 +189,320 @@ pub enum DnsRequest {
     },
     /// A request sent by the taker to fetch all valid maker addresses from the DNS server.
     Get,
-    /// To gauge server activity
-    Pong { address: String },
+    /// A maker's answer to [`DnsResponse::Ping`].
+    Pong {
+        address: String,
+        /// Signature by the bond pubkey over the ping hash, see [`ping_hash`]. Makers that
+        /// predate challenges leave it out, and are not considered live.
+        ///
+        /// [`ping_hash`]: crate::fidelity::ping_hash
+        #[serde(default)]
+        signature: Option<Signature>,
+    },
+    /// A request sent by the taker to fetch one page of the active makers matching `filter`.
+    Query { filter: MakerFilter },
+    /// A request sent by the taker for `count` active makers drawn at random, each weighted by
+    /// its bond value, so that swaps don't all land on the top-ranked makers.
+    Sample {
+        count: u32,
+        /// Makes the draw reproducible; meant for tests.
+        #[serde(default)]
+        seed: Option<u64>,
+        /// Answer with full [`MakerRecord`]s rather than bare addresses.
+        #[serde(default)]
+        records: bool,
+    },
+    /// A request sent by the maker to report that it is still up, sparing it a probe.
+    Heartbeat {
+        address: String,
+        /// Unix time the heartbeat was made at; must be close to the tracker's clock.
+        timestamp: u64,
+        /// Signature by the bond pubkey over the heartbeat hash, see [`heartbeat_hash`].
+        ///
+        /// [`heartbeat_hash`]: crate::fidelity::heartbeat_hash
+        signature: Signature,
+    },
+}
+
+impl DnsRequest {
+    /// Features a versioned session must have negotiated to make this request.
+    pub fn required_features(&self) -> Features {
+        match self {
+            DnsRequest::Post { .. } | DnsRequest::Get | DnsRequest::Pong { .. } => Features::NONE,
+            DnsRequest::Query { filter } if filter.records => {
+                Features::FILTERED_QUERY.union(Features::RECORDS)
+            }
+            DnsRequest::Query { .. } => Features::FILTERED_QUERY,
+            DnsRequest::Sample { records: true, .. } => Features::SAMPLE.union(Features::RECORDS),
+            DnsRequest::Sample { .. } => Features::SAMPLE,
+            DnsRequest::Heartbeat { .. } => Features::HEARTBEAT,
+        }
+    }
+}
+
+/// Constraints on the makers returned for a [`DnsRequest::Query`]. The default matches every
+/// active maker.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MakerFilter {
+    /// Most makers to return, capped by the tracker's own page size.
+    #[serde(default)]
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub min_bond_value: Option<Amount>,
+    /// Minimum time, in seconds, the maker must have been reachable without interruption.
+    #[serde(default)]
+    pub min_uptime: Option<u64>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Where to resume, as returned with the previous page.
+    #[serde(default)]
+    pub cursor: Option<Cursor>,
+    /// Answer with full [`MakerRecord`]s rather than bare addresses.
+    #[serde(default)]
+    pub records: bool,
+}
+
+/// Position in the order query pages follow. Opaque to clients.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    pub(crate) amount: Amount,
+    pub(crate) lock_time: LockTime,
+    pub(crate) address: String,
+}
+
+/// What the tracker knows about a maker, so takers can choose without contacting each one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MakerRecord {
+    pub address: String,
+    pub outpoint: OutPoint,
+    pub amount: Amount,
+    pub lock_time: LockTime,
+    /// The bond value score makers are ranked by.
+    pub bond_value: Amount,
+    /// Height the maker's announcement was first found at, if it was found on chain.
+    pub found_height: Option<u64>,
+    /// Unix time of the last successful ping.
+    pub last_ping: Option<u64>,
+    pub stale: bool,
+    /// Percentage of recent probes the maker answered.
+    pub uptime: Option<f64>,
+    /// Median round-trip time of recent probes, in milliseconds.
+    pub median_latency_ms: Option<u64>,
+}
+
+impl MakerRecord {
+    pub fn new(address: &str, info: &ServerInfo) -> Self {
+        MakerRecord {
+            address: address.to_string(),
+            outpoint: info.bond.outpoint,
+            amount: info.bond.amount,
+            lock_time: info.bond.lock_time,
+            bond_value: info.bond_value,
+            found_height: info.found_height,
+            last_ping: info.last_ping.map(to_unix),
+            stale: info.stale,
+            uptime: info.history.uptime(),
+            median_latency_ms: info
+                .history
+                .median_latency()
+                .map(|latency| latency.as_millis() as u64),
+        }
+    }
+}
+
+/// A page of [`MakerRecord`]s, as returned by the DB manager for a filtered query.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RecordPage {
+    pub records: Vec<MakerRecord>,
+    pub next_cursor: Option<Cursor>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MakerPage {
+    pub addresses: Vec<String>,
+    /// Set when more makers match, to be passed back in the next query's filter.
+    pub next_cursor: Option<Cursor>,
+}
+
+/// Why a maker's registration or heartbeat was turned down.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The URL is not an `<domain>.onion:<port>` address.
+    InvalidAddress,
+    /// The certificate has no expiry or has already expired.
+    CertExpired,
+    /// The certificate hash does not commit to this bond and URL.
+    CertHashMismatch,
+    /// The certificate is not signed by the bond pubkey.
+    InvalidSignature,
+    /// The bond outpoint is not a confirmed, unspent output.
+    BondNotFound,
+    /// The bond output does not pay the advertised amount to the bond script.
+    BondMismatch,
+    /// The heartbeat timestamp is too far from the tracker's clock.
+    StaleTimestamp,
+    /// The bond has a zero locktime, so it was never locked.
+    BondNotLocked,
+    /// The address is registered under another bond key. The new bond takes over once the maker
+    /// answers a probe signed with its key.
+    AddressTaken,
+    /// The bond already backs another address.
+    BondInUse,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DnsResponse {
-    Address { addresses: Vec<String> },
-    Ping,
+    Address {
+        addresses: Vec<String>,
+    },
+    /// A liveness probe: the maker proves it holds its bond key by signing `nonce`.
+    Ping {
+        nonce: [u8; 32],
+    },
+    /// The maker's registration was accepted.
+    Accepted,
+    /// A page of makers, in reply to a [`DnsRequest::Query`].
+    Page {
+        page: MakerPage,
+    },
+    /// Makers with their full records, for requests that asked for them.
+    Records {
+        page: RecordPage,
+    },
+    /// The request could not be served.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl DnsResponse {
+    pub fn error(code: ErrorCode, message: impl ToString) -> Self {
+        DnsResponse::Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Machine-readable category of a [`DnsResponse::Error`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The message could not be decoded, or was too large.
+    MalformedRequest,
+    /// The client sent too many requests; it may retry later on the same connection.
+    RateLimited,
+    /// A maker's registration was turned down.
+    InvalidProof(RejectReason),
+    /// The maker is not in the registry.
+    UnknownMaker,
+    /// The request is only valid in reply to one from the tracker.
+    Unexpected,
+    /// The client's protocol version is too old to be served.
+    UnsupportedVersion,
+    /// An enveloped request arrived before the client's handshake.
+    HandshakeRequired,
+    /// The tracker failed to serve an otherwise valid request.
+    Internal,
+}
+
+/// Wire protocol version spoken by this tracker.
+///
+/// Clients that open with a bare [`DnsRequest`] instead of a [`Handshake`] predate versioning and
+/// are answered with bare [`DnsResponse`]s.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version a handshake may negotiate.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features, one bit each.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features(pub u64);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    /// [`DnsRequest::Query`] is understood.
+    pub const FILTERED_QUERY: Features = Features(1 << 0);
+    /// [`DnsRequest::Sample`] is understood.
+    pub const SAMPLE: Features = Features(1 << 1);
+    /// Queries and samples can be answered with [`MakerRecord`]s.
+    pub const RECORDS: Features = Features(1 << 2);
+    /// [`DnsRequest::Heartbeat`] is understood.
+    pub const HEARTBEAT: Features = Features(1 << 3);
+    /// Everything this tracker supports.
+    pub const SUPPORTED: Features = Features(
+        Features::FILTERED_QUERY.0
+            | Features::SAMPLE.0
+            | Features::RECORDS.0
+            | Features::HEARTBEAT.0,
+    );
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+
+    pub const fn union(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+/// Opens a versioned session: each side sends what it speaks, and both then use the highest
+/// common version and the features they share.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u32,
+    pub features: Features,
+}
+
+impl Handshake {
+    pub fn ours() -> Self {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            features: Features::SUPPORTED,
+        }
+    }
+
+    /// The session parameters agreed on with a peer that sent `theirs`, if its version is
+    /// still supported.
+    pub fn negotiate(theirs: Handshake) -> Option<Self> {
+        if theirs.version < MIN_PROTOCOL_VERSION {
+            return None;
+        }
+        let ours = Handshake::ours();
+        Some(Handshake {
+            version: ours.version.min(theirs.version),
+            features: ours.features.intersection(theirs.features),
+        })
+    }
+}
+
+/// A message from a versioned client.
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum ClientMessage {
+    Handshake(Handshake),
+    /// A request, answered by a [`ServerMessage::Response`] carrying the same `id`.
+    Request {
+        id: u64,
+        request: DnsRequest,
+    },
+}
+
+/// A message to a versioned client.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ServerMessage {
+    /// The negotiated session parameters, in reply to the client's handshake.
+    Handshake(Handshake),
+    Response {
+        id: u64,
+        response: DnsResponse,
+    },
+    /// A failure not tied to any request, such as a refused handshake or an unreadable message.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
 }
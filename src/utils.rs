@@ -1,32 +1,203 @@
+use std::{
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{FutureExt, SinkExt, StreamExt};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::tcp::{ReadHalf, WriteHalf},
+    io::{AsyncRead, AsyncWrite},
+    time::{Instant, timeout},
+};
+use tokio_graceful::ShutdownGuard;
+use tokio_util::{
+    bytes::{Bytes, BytesMut},
+    codec::{Framed, LengthDelimitedCodec},
 };
 
 use crate::error::TrackerError;
 
-pub async fn read_message(reader: &mut BufReader<ReadHalf<'_>>) -> Result<Vec<u8>, TrackerError> {
-    // length of incoming data
-    let mut len_buff = [0u8; 4];
-    reader.read_exact(&mut len_buff).await?;
-    let length = u32::from_be_bytes(len_buff);
-    let mut buffer = vec![0; length as usize];
+/// Largest message accepted from a peer. Anything bigger is a protocol violation, not a request.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+/// How long a peer gets to deliver a complete message.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(60);
 
-    _ = reader.read(&mut buffer[4..]).await?;
+/// A connection carrying CBOR messages, each prefixed with its length as a big-endian `u32`.
+pub type MessageStream<T> = Framed<T, LengthDelimitedCodec>;
 
-    Ok(buffer)
+/// Wraps `io` in the message framing, refusing frames over `max_frame_size` bytes before
+/// allocating for them.
+pub fn message_stream<T: AsyncRead + AsyncWrite>(io: T, max_frame_size: usize) -> MessageStream<T> {
+    let codec = LengthDelimitedCodec::builder()
+        .length_field_type::<u32>()
+        .big_endian()
+        .max_frame_length(max_frame_size)
+        .new_codec();
+    Framed::new(io, codec)
 }
 
-pub async fn send_message(
-    writer: &mut BufWriter<WriteHalf<'_>>,
+/// Reads the next complete message, failing if the peer takes longer than `READ_TIMEOUT` or
+/// closes the connection.
+pub async fn read_message<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut MessageStream<T>,
+) -> Result<BytesMut, TrackerError> {
+    match timeout(READ_TIMEOUT, stream.next()).await {
+        Ok(Some(frame)) => Ok(frame?),
+        Ok(None) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+    }
+}
+
+pub async fn send_message<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut MessageStream<T>,
     message: &impl serde::Serialize,
 ) -> Result<(), TrackerError> {
     let msg_bytes = serde_cbor::ser::to_vec(message)?;
-    let msg_len = (msg_bytes.len() as u32).to_be_bytes();
-    let mut to_send = Vec::with_capacity(msg_bytes.len() + msg_len.len());
-    to_send.extend(msg_len);
-    to_send.extend(msg_bytes);
-    writer.write_all(&to_send).await?;
-    writer.flush().await?;
+    stream.send(Bytes::from(msg_bytes)).await?;
     Ok(())
 }
+
+/// Checks for an `<domain>.onion:<port>` address with a non-zero port.
+pub fn is_valid_onion_address(s: &str) -> bool {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() != 2 {
+        return false;
+    }
+    let domain = parts[0];
+    let port = parts[1];
+    if !domain.ends_with(".onion") {
+        return false;
+    }
+    matches!(port.parse::<u16>(), Ok(p) if p > 0)
+}
+
+/// Whether a shutdown has been requested, without waiting for one.
+pub fn shutdown_requested(guard: &ShutdownGuard) -> bool {
+    guard.cancelled().now_or_never().is_some()
+}
+
+/// Wall-clock time of `instant`, in seconds since the unix epoch.
+pub fn to_unix(instant: Instant) -> u64 {
+    SystemTime::now()
+        .checked_sub(instant.elapsed())
+        .unwrap_or(UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The `Instant` corresponding to a unix timestamp, clamped to now for future times.
+pub fn from_unix(secs: u64) -> Instant {
+    let age = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+        .saturating_sub(secs);
+    let now = Instant::now();
+    now.checked_sub(Duration::from_secs(age)).unwrap_or(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use tokio::io::{AsyncWriteExt, DuplexStream, duplex};
+
+    use super::*;
+
+    /// A framed end of an in-memory connection, and the raw other end to play the peer with.
+    fn connection() -> (MessageStream<DuplexStream>, DuplexStream) {
+        let (ours, theirs) = duplex(1024);
+        (message_stream(ours, MAX_FRAME_SIZE), theirs)
+    }
+
+    fn io_error_kind(result: Result<BytesMut, TrackerError>) -> io::ErrorKind {
+        match result {
+            Err(TrackerError::IOError(e)) => e.kind(),
+            other => panic!("expected an I/O error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_arbitrary_payloads() {
+        let (ours, theirs) = duplex(1024);
+        let mut sender = message_stream(ours, MAX_FRAME_SIZE);
+        let mut receiver = message_stream(theirs, MAX_FRAME_SIZE);
+        let mut rng = StdRng::seed_from_u64(11);
+        for _ in 0..200 {
+            let len = rng.gen_range(0..MAX_FRAME_SIZE / 2);
+            let bytes: Vec<u8> = (0..len).map(|_| rng.r#gen()).collect();
+            let text: String = (0..rng.gen_range(0..64))
+                .map(|_| rng.gen_range('\u{20}'..'\u{2fff}'))
+                .collect();
+            let message = (text, bytes, rng.r#gen::<u64>());
+
+            let (sent, received) = tokio::join!(
+                send_message(&mut sender, &message),
+                read_message(&mut receiver)
+            );
+            sent.unwrap();
+            let decoded: (String, Vec<u8>, u64) =
+                serde_cbor::de::from_slice(&received.unwrap()).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_frame_of_max_size() {
+        let (mut stream, mut peer) = connection();
+        let payload: Vec<u8> = (0..MAX_FRAME_SIZE).map(|i| i as u8).collect();
+        let write = async {
+            peer.write_all(&(MAX_FRAME_SIZE as u32).to_be_bytes())
+                .await
+                .unwrap();
+            peer.write_all(&payload).await.unwrap();
+        };
+        let (_, frame) = tokio::join!(write, read_message(&mut stream));
+        assert_eq!(frame.unwrap(), payload);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_frame_before_allocating() {
+        let (mut stream, mut peer) = connection();
+        // Claims a gigabyte but sends nothing more: the length alone must be enough to refuse it.
+        peer.write_all(&(1u32 << 30).to_be_bytes()).await.unwrap();
+        let result = read_message(&mut stream).await;
+        assert_eq!(io_error_kind(result), io::ErrorKind::InvalidData);
+        assert!(stream.read_buffer().capacity() < MAX_FRAME_SIZE);
+
+        let (mut stream, mut peer) = connection();
+        peer.write_all(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes())
+            .await
+            .unwrap();
+        let result = read_message(&mut stream).await;
+        assert_eq!(io_error_kind(result), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn fails_on_truncated_frame() {
+        let (mut stream, mut peer) = connection();
+        peer.write_all(&100u32.to_be_bytes()).await.unwrap();
+        peer.write_all(&[0; 10]).await.unwrap();
+        drop(peer);
+        assert!(matches!(
+            read_message(&mut stream).await,
+            Err(TrackerError::IOError(_))
+        ));
+
+        // A connection closed cleanly between frames is reported as such.
+        let (mut stream, peer) = connection();
+        drop(peer);
+        let result = read_message(&mut stream).await;
+        assert_eq!(io_error_kind(result), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_on_stalled_peer() {
+        let (mut stream, mut peer) = connection();
+        peer.write_all(&100u32.to_be_bytes()).await.unwrap();
+        peer.write_all(&[0; 10]).await.unwrap();
+        let started = Instant::now();
+        let result = read_message(&mut stream).await;
+        assert_eq!(io_error_kind(result), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= READ_TIMEOUT);
+        drop(peer);
+    }
+}